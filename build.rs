@@ -1,3 +1,5 @@
+use std::{env, path::PathBuf};
+
 use vergen_git2::{Emitter, Git2Builder};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -26,6 +28,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .protoc_arg("--experimental_allow_proto3_optional")
         // Make all messages serde-serializable.
         .type_attribute(".", "#[derive(serde::Serialize,serde::Deserialize)]")
+        // File descriptor set for gRPC server reflection.
+        .file_descriptor_set_path(
+            PathBuf::from(env::var("OUT_DIR")?).join("proxy_descriptor.bin"),
+        )
         // Compiling protos using path on build time.
         .compile_protos(&["proto/core/proxy.proto"], &["proto/core"])?;
 
@@ -0,0 +1,169 @@
+use std::fmt;
+
+use ipnet::IpNet;
+use tonic::{service::Interceptor, Request, Status};
+
+const AUTHORIZATION_HEADER: &str = "authorization";
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// Decides which clients may connect to the gRPC server, based on their source address
+/// and a shared secret token sent in `authorization` metadata.
+#[derive(Clone)]
+pub(crate) struct AdmissionInterceptor {
+    allowed_cidrs: Vec<IpNet>,
+    token: Option<String>,
+}
+
+impl fmt::Debug for AdmissionInterceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdmissionInterceptor")
+            .field("allowed_cidrs", &self.allowed_cidrs)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl AdmissionInterceptor {
+    #[must_use]
+    pub(crate) fn new(allowed_cidrs: Vec<IpNet>, token: Option<String>) -> Self {
+        Self {
+            allowed_cidrs,
+            token,
+        }
+    }
+
+    fn check(&self, request: &Request<()>) -> Result<(), &'static str> {
+        if !self.allowed_cidrs.is_empty() {
+            let Some(addr) = request.remote_addr() else {
+                return Err("unknown source address");
+            };
+            let ip = addr.ip().to_canonical();
+            if !self.allowed_cidrs.iter().any(|cidr| cidr.contains(&ip)) {
+                return Err("source address not allowed");
+            }
+        }
+
+        if let Some(expected) = &self.token {
+            let token = request
+                .metadata()
+                .get(AUTHORIZATION_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix(BEARER_PREFIX));
+            match token {
+                None => return Err("missing token"),
+                Some(token) if !constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+                    return Err("invalid token")
+                }
+                Some(_) => (),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Interceptor for AdmissionInterceptor {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        match self.check(&request) {
+            Ok(()) => Ok(request),
+            Err(reason) => {
+                let addr = request
+                    .remote_addr()
+                    .map_or_else(|| "unknown".to_string(), |addr| addr.to_string());
+                warn!("Rejected Defguard Core connection from {addr}: {reason}");
+                Err(Status::permission_denied("Connection not allowed"))
+            }
+        }
+    }
+}
+
+// Compares secrets in time independent of the position of the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tonic::transport::server::TcpConnectInfo;
+
+    use super::*;
+
+    fn request(addr: Option<&str>, authorization: Option<&str>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(addr) = addr {
+            request.extensions_mut().insert(TcpConnectInfo {
+                local_addr: None,
+                remote_addr: Some(addr.parse::<SocketAddr>().unwrap()),
+            });
+        }
+        if let Some(authorization) = authorization {
+            request
+                .metadata_mut()
+                .insert(AUTHORIZATION_HEADER, authorization.parse().unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn test_admission_check() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+
+        // everything is allowed by default
+        let interceptor = AdmissionInterceptor::new(Vec::new(), None);
+        assert_eq!(interceptor.check(&request(None, None)), Ok(()));
+
+        let interceptor = AdmissionInterceptor::new(
+            vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            None,
+        );
+        assert_eq!(
+            interceptor.check(&request(Some("10.1.2.3:4711"), None)),
+            Ok(())
+        );
+        assert_eq!(
+            interceptor.check(&request(Some("[fd00::1]:4711"), None)),
+            Ok(())
+        );
+        // IPv4-mapped IPv6 address of a dual-stack listener
+        assert_eq!(
+            interceptor.check(&request(Some("[::ffff:10.1.2.3]:4711"), None)),
+            Ok(())
+        );
+        assert_eq!(
+            interceptor.check(&request(Some("192.168.1.1:4711"), None)),
+            Err("source address not allowed")
+        );
+        assert_eq!(
+            interceptor.check(&request(None, None)),
+            Err("unknown source address")
+        );
+
+        let mut interceptor = AdmissionInterceptor::new(Vec::new(), Some("secret".into()));
+        assert!(!format!("{interceptor:?}").contains("secret"));
+        assert_eq!(
+            interceptor.check(&request(None, Some("Bearer secret"))),
+            Ok(())
+        );
+        assert_eq!(
+            interceptor.check(&request(None, None)),
+            Err("missing token")
+        );
+        assert_eq!(
+            interceptor.check(&request(None, Some("secret"))),
+            Err("missing token")
+        );
+        assert_eq!(
+            interceptor.check(&request(None, Some("Bearer secret2"))),
+            Err("invalid token")
+        );
+        let status = interceptor
+            .call(request(None, Some("Bearer wrong")))
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+}
@@ -0,0 +1,380 @@
+use std::{
+    fs::read_to_string,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
+};
+
+use anyhow::bail;
+use clap::Subcommand;
+use schemars::schema_for;
+
+use crate::{
+    config::Config,
+    cookies::parse_key,
+    tls::{certified_key, client_config, client_verifier},
+};
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Configuration utilities
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Validate configuration and print effective values without starting the servers
+    Check,
+    /// Print JSON Schema of the configuration file
+    Schema,
+}
+
+/// Run CLI subcommand instead of starting the servers.
+pub fn run_command(command: &Command, config: &Config) -> anyhow::Result<()> {
+    match command {
+        Command::Config(ConfigCommand::Check) => check_config(config),
+        Command::Config(ConfigCommand::Schema) => {
+            println!("{}", serde_json::to_string_pretty(&schema_for!(Config))?);
+            Ok(())
+        }
+    }
+}
+
+fn check_config(config: &Config) -> anyhow::Result<()> {
+    println!("Effective configuration:");
+    for (name, value, source) in config.effective_values() {
+        println!("  {name} = {value} ({source})");
+    }
+
+    let mut errors = config_errors(config);
+    errors.extend(bind_errors(config));
+    if errors.is_empty() {
+        println!("Configuration is valid");
+        Ok(())
+    } else {
+        for error in &errors {
+            eprintln!("Error: {error}");
+        }
+        bail!("Configuration check failed with {} error(s)", errors.len())
+    }
+}
+
+// Returns names, addresses and ports of listeners started with the configuration.
+fn listeners(config: &Config) -> Vec<(&'static str, IpAddr, u16)> {
+    let unspecified = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    let http_address = config.http_bind_address.unwrap_or(unspecified);
+    let mut listeners = vec![("http_port", http_address, config.http_port)];
+    // gRPC server has its own listener, unless it shares the API port or dials Defguard Core
+    if !config.grpc_single_port && config.core_url.is_none() {
+        listeners.push((
+            "grpc_port",
+            config.grpc_bind_address.unwrap_or(unspecified),
+            config.grpc_port,
+        ));
+    }
+    if let Some(port) = config.http_redirect_port {
+        listeners.push(("http_redirect_port", http_address, port));
+    }
+    if let Some(port) = config.metrics_port.filter(|_| config.metrics) {
+        listeners.push((
+            "metrics_port",
+            config.metrics_bind_address.unwrap_or(unspecified),
+            port,
+        ));
+    }
+    listeners
+}
+
+// Returns problems with binding listeners, like ports already in use or privileged ones.
+fn bind_errors(config: &Config) -> Vec<String> {
+    listeners(config)
+        .into_iter()
+        .filter_map(|(name, address, port)| {
+            let addr = SocketAddr::new(address, port);
+            TcpListener::bind(addr)
+                .err()
+                .map(|err| format!("{name}: can't bind to {addr}: {err}"))
+        })
+        .collect()
+}
+
+/// Returns descriptions of all problems found in the configuration.
+/// Doesn't check if listening addresses can be bound, since that's done when starting servers.
+pub(crate) fn config_errors(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    // TLS certificates and keys
+    for (cert_name, key_name, cert_path, key_path) in [
+        ("grpc_cert", "grpc_key", &config.grpc_cert, &config.grpc_key),
+        ("http_cert", "http_key", &config.http_cert, &config.http_key),
+    ] {
+        match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let key_pair = read_to_string(cert_path)
+                    .and_then(|cert| read_to_string(key_path).map(|key| (cert, key)));
+                match key_pair {
+                    Ok((cert, key)) => {
+                        if let Err(err) = certified_key(&cert, &key) {
+                            errors.push(format!("{cert_name}/{key_name}: {err}"));
+                        }
+                    }
+                    Err(err) => errors.push(format!("{cert_name}/{key_name}: {err}")),
+                }
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                errors.push(format!("{cert_name} and {key_name} must be set together"));
+            }
+            (None, None) => (),
+        }
+    }
+
+    // gRPC client certificate verification
+    if let Some(ca_path) = &config.grpc_client_ca {
+        if config.grpc_cert.is_none() {
+            errors.push("grpc_client_ca requires grpc_cert and grpc_key".into());
+        }
+        if let Err(err) = client_verifier(ca_path, None) {
+            errors.push(format!("grpc_client_ca: {err}"));
+        }
+    } else if config.grpc_client_allowed_name.is_some() {
+        errors.push("grpc_client_allowed_name requires grpc_client_ca".into());
+    }
+
+    // outbound connection to Defguard Core
+    if let Some(url) = &config.core_url {
+        // TODO: allow once the `CoreProxy` service is added to the proto definitions and served
+        // by Defguard Core, the proxy can't be connected to Core until then.
+        errors.push(
+            "core_url: outbound mode is not supported yet, Defguard Core doesn't serve \
+            the CoreProxy service"
+                .into(),
+        );
+        match url.scheme() {
+            "https" => {
+                if let Err(err) = client_config(config.core_ca.as_deref(), None, Vec::new()) {
+                    errors.push(format!("core_ca: {err}"));
+                }
+            }
+            "http" => (),
+            scheme => errors.push(format!("core_url: unsupported scheme {scheme}")),
+        }
+        if url.host().is_none() {
+            errors.push("core_url: missing host".into());
+        }
+        if config.grpc_client_ca.is_some() {
+            errors.push("grpc_client_ca can't be used together with core_url".into());
+        }
+        if !config.grpc_allowed_cidrs.is_empty() {
+            errors.push("grpc_allowed_cidrs can't be used together with core_url".into());
+        }
+        if config.grpc_token.is_some() {
+            errors.push("grpc_token can't be used together with core_url".into());
+        }
+    } else if config.core_ca.is_some() {
+        errors.push("core_ca requires core_url".into());
+    }
+    if config.grpc_single_port {
+        if config.core_url.is_some() {
+            errors.push("grpc_single_port can't be used together with core_url".into());
+        }
+        if config.grpc_client_ca.is_some() {
+            errors.push("grpc_client_ca can't be used together with grpc_single_port".into());
+        }
+        // gRPC is served with the API server TLS configuration
+        if config.grpc_cert.is_some() || config.grpc_key.is_some() {
+            errors
+                .push("grpc_cert and grpc_key can't be used together with grpc_single_port".into());
+        }
+    }
+    // PROXY protocol header is only accepted from trusted proxies
+    for (name, enabled) in [
+        ("http_proxy_protocol", config.http_proxy_protocol),
+        ("grpc_proxy_protocol", config.grpc_proxy_protocol),
+    ] {
+        if enabled && config.trusted_proxies.is_empty() {
+            errors.push(format!("{name} requires trusted_proxies"));
+        }
+    }
+    if config.grpc_proxy_protocol {
+        if config.core_url.is_some() {
+            errors.push("grpc_proxy_protocol can't be used together with core_url".into());
+        }
+        if config.grpc_single_port {
+            errors.push("grpc_proxy_protocol can't be used together with grpc_single_port".into());
+        }
+    }
+
+    // OpenTelemetry export
+    if let Some(url) = &config.otlp_endpoint {
+        if !matches!(url.scheme(), "http" | "https") {
+            errors.push(format!(
+                "otlp_endpoint: unsupported scheme {}",
+                url.scheme()
+            ));
+        }
+    }
+    if !(0.0..=1.0).contains(&config.otlp_sampling_ratio) {
+        errors.push("otlp_sampling_ratio must be between 0 and 1".into());
+    }
+    if !config.metrics && config.metrics_port.is_some() {
+        errors.push("metrics_port requires metrics".into());
+    }
+    if config.metrics_port.is_none() && config.metrics_bind_address.is_some() {
+        errors.push("metrics_bind_address requires metrics_port".into());
+    }
+
+    // rate limit policies
+    for (name, policy) in &config.rate_limit_policies {
+        if policy.routes.is_empty() {
+            errors.push(format!(
+                "rate_limit_policies.{name}: routes must not be empty"
+            ));
+        }
+        if let Some(route) = policy.routes.iter().find(|route| !route.starts_with('/')) {
+            errors.push(format!(
+                "rate_limit_policies.{name}: route {route} must start with /"
+            ));
+        }
+        if !policy.by_ip && policy.by_fields.is_empty() {
+            errors.push(format!(
+                "rate_limit_policies.{name}: by_ip or by_fields must be set"
+            ));
+        }
+    }
+
+    if config.lockout_threshold > 0 && (config.lockout_window == 0 || config.lockout_duration == 0)
+    {
+        errors.push("lockout_window and lockout_duration must be greater than 0".into());
+    }
+
+    if config.grpc_token.as_ref().is_some_and(String::is_empty) {
+        errors.push("grpc_token must not be empty".into());
+    }
+
+    // cookie encryption keys
+    if let Some(key) = &config.cookie_key {
+        if let Err(err) = parse_key(key) {
+            errors.push(format!("cookie_key: {err}"));
+        }
+    }
+    if let Some(path) = &config.cookie_key_file {
+        // missing file is fine, it's created on startup
+        match read_to_string(path) {
+            Ok(key) => {
+                if let Err(err) = parse_key(&key) {
+                    errors.push(format!("cookie_key_file: {err}"));
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => (),
+            Err(err) => errors.push(format!("cookie_key_file: {err}")),
+        }
+    }
+    for (index, key) in config.cookie_previous_keys.iter().enumerate() {
+        if let Err(err) = parse_key(key) {
+            errors.push(format!("cookie_previous_keys[{index}]: {err}"));
+        }
+    }
+
+    // listening ports
+    let listeners = listeners(config);
+    for (index, (name, _, port)) in listeners.iter().enumerate() {
+        if let Some((other, _, _)) = listeners[..index]
+            .iter()
+            .find(|(_, _, other_port)| other_port == port)
+        {
+            errors.push(format!("{other} and {name} must be different"));
+        }
+    }
+    if config.http_redirect_port.is_some() && config.http_cert.is_none() {
+        errors.push("http_redirect_port requires http_cert and http_key".into());
+    }
+
+    // public URL
+    if !matches!(config.url.scheme(), "http" | "https") {
+        errors.push(format!("url: unsupported scheme {}", config.url.scheme()));
+    }
+    if config.url.host().is_none() {
+        errors.push("url: missing host".into());
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_errors() {
+        let mut config = Config::parse_without_env(["defguard-proxy"]);
+        assert!(config_errors(&config).is_empty());
+
+        config.grpc_port = config.http_port;
+        assert_eq!(
+            config_errors(&config),
+            ["http_port and grpc_port must be different"]
+        );
+        // gRPC port is not used without a separate gRPC listener
+        config.grpc_single_port = true;
+        assert!(config_errors(&config).is_empty());
+        // gRPC uses the API server certificate in single port mode
+        config.grpc_key = Some("key.pem".into());
+        assert_eq!(
+            config_errors(&config),
+            [
+                "grpc_cert and grpc_key must be set together",
+                "grpc_cert and grpc_key can't be used together with grpc_single_port"
+            ]
+        );
+        config.grpc_key = None;
+        config.grpc_single_port = false;
+        config.core_url = Some("https://core.example.com".parse().unwrap());
+        let unsupported = "core_url: outbound mode is not supported yet, Defguard Core doesn't \
+            serve the CoreProxy service";
+        assert_eq!(config_errors(&config), [unsupported]);
+        config.grpc_token = Some("token".into());
+        assert_eq!(
+            config_errors(&config),
+            [
+                unsupported,
+                "grpc_token can't be used together with core_url"
+            ]
+        );
+        config.grpc_token = None;
+        config.core_url = None;
+        config.grpc_port = 50051;
+
+        // PROXY protocol header is only accepted from trusted proxies
+        config.http_proxy_protocol = true;
+        assert_eq!(
+            config_errors(&config),
+            ["http_proxy_protocol requires trusted_proxies"]
+        );
+        config.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()];
+        assert!(config_errors(&config).is_empty());
+        config.http_proxy_protocol = false;
+        config.trusted_proxies = Vec::new();
+
+        config.http_cert = Some("cert.pem".into());
+        config.http_redirect_port = Some(config.http_port);
+        assert_eq!(
+            config_errors(&config),
+            [
+                "http_cert and http_key must be set together",
+                "http_port and http_redirect_port must be different"
+            ]
+        );
+        config.http_cert = None;
+        config.http_redirect_port = None;
+
+        // ports in use
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        config.http_bind_address = Some(Ipv4Addr::LOCALHOST.into());
+        config.http_port = listener.local_addr().unwrap().port();
+        config.grpc_bind_address = Some(Ipv4Addr::LOCALHOST.into());
+        config.grpc_port = 0;
+        let errors = bind_errors(&config);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("http_port: can't bind to 127.0.0.1:"));
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{header::FORWARDED, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+use tower_governor::{key_extractor::KeyExtractor, GovernorError};
+
+use crate::config::ForwardedHeader;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Client IP address resolved by `client_ip_middleware`, stored in request extensions.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClientIp(pub(crate) IpAddr);
+
+/// Resolves client IP address of requests passing through trusted reverse proxies.
+/// The forwarding header is only considered if the peer is a trusted proxy. The addresses it
+/// contains are then walked from the right, skipping trusted proxies, so the first untrusted
+/// address is the client. Only the configured header is used: proxies usually append to the one
+/// they set, but pass the other one from the client unchanged.
+#[derive(Clone, Debug)]
+pub(crate) struct ClientIpResolver {
+    trusted_proxies: Arc<Vec<IpNet>>,
+    header: ForwardedHeader,
+}
+
+impl ClientIpResolver {
+    #[must_use]
+    pub(crate) fn new(trusted_proxies: Vec<IpNet>, header: ForwardedHeader) -> Self {
+        Self {
+            trusted_proxies: Arc::new(trusted_proxies),
+            header,
+        }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Resolve client IP address of a request received from `peer`.
+    #[must_use]
+    pub(crate) fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer.to_canonical();
+        if !self.is_trusted(client) {
+            return client;
+        }
+        let hops = match self.header {
+            ForwardedHeader::XForwardedFor => x_forwarded_for_hops(headers),
+            ForwardedHeader::Forwarded => forwarded_hops(headers),
+        };
+        for hop in hops.into_iter().rev() {
+            if !self.is_trusted(client) {
+                break;
+            }
+            // unknown and obfuscated addresses end the chain
+            match hop {
+                Some(ip) => client = ip.to_canonical(),
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+// Parses forwarded address, optionally with port or in brackets.
+fn parse_hop(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    value
+        .parse()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            value
+                .strip_prefix('[')
+                .and_then(|value| value.strip_suffix(']'))
+                .and_then(|value| value.parse().ok())
+        })
+}
+
+fn x_forwarded_for_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .map(parse_hop)
+        .collect()
+}
+
+// Each `Forwarded` element is a hop, identified by its `for` parameter.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(FORWARDED)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_hop(value))
+                    .flatten()
+            })
+        })
+        .collect()
+}
+
+/// Resolve client IP address and store it in request extensions as `ClientIp`.
+/// Requires `ConnectInfo<SocketAddr>`, the address isn't resolved without it.
+pub(crate) async fn client_ip_middleware(
+    State(resolver): State<ClientIpResolver>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    if let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let ip = resolver.resolve(addr.ip(), request.headers());
+        request.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(request).await
+}
+
+/// Rate limiter key extractor using address resolved by `client_ip_middleware`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClientIpKeyExtractor;
+
+impl KeyExtractor for ClientIpKeyExtractor {
+    type Key = IpAddr;
+
+    fn extract<T>(&self, request: &axum::http::Request<T>) -> Result<Self::Key, GovernorError> {
+        request
+            .extensions()
+            .get::<ClientIp>()
+            .map(|client_ip| client_ip.0)
+            .ok_or(GovernorError::UnableToExtractKey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_resolve_client_ip() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()];
+        let resolver = ClientIpResolver::new(trusted.clone(), ForwardedHeader::XForwardedFor);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("1.1.1.1, 2.2.2.2, 10.0.0.2"),
+        );
+        // headers of untrusted peers are ignored
+        assert_eq!(resolver.resolve(ip("3.3.3.3"), &headers), ip("3.3.3.3"));
+        // spoofed leftmost address is skipped
+        assert_eq!(resolver.resolve(ip("10.0.0.1"), &headers), ip("2.2.2.2"));
+        // IPv4-mapped IPv6 peer address
+        assert_eq!(
+            resolver.resolve(ip("::ffff:10.0.0.1"), &headers),
+            ip("2.2.2.2")
+        );
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static("10.0.0.3"));
+        assert_eq!(resolver.resolve(ip("::1"), &headers), ip("2.2.2.2"));
+
+        // all hops trusted
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("10.0.0.3"));
+        assert_eq!(resolver.resolve(ip("10.0.0.1"), &headers), ip("10.0.0.3"));
+        // invalid address ends the chain
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("1.1.1.1, garbage, 10.0.0.2"),
+        );
+        assert_eq!(resolver.resolve(ip("10.0.0.1"), &headers), ip("10.0.0.2"));
+
+        // `Forwarded` sent by the client is ignored behind an `X-Forwarded-For` proxy
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("2.2.2.2"));
+        headers.insert(FORWARDED, HeaderValue::from_static("for=1.1.1.1"));
+        assert_eq!(resolver.resolve(ip("10.0.0.1"), &headers), ip("2.2.2.2"));
+
+        // and the other way round
+        let resolver = ClientIpResolver::new(trusted, ForwardedHeader::Forwarded);
+        headers.insert(
+            FORWARDED,
+            HeaderValue::from_static(
+                "for=1.1.1.1, For=\"[2001:db8::17]:4711\";proto=https, for=10.0.0.2:80;by=10.0.0.1",
+            ),
+        );
+        assert_eq!(
+            resolver.resolve(ip("10.0.0.1"), &headers),
+            ip("2001:db8::17")
+        );
+        headers.insert(
+            FORWARDED,
+            HeaderValue::from_static("for=1.1.1.1, for=_hidden, for=10.0.0.2"),
+        );
+        assert_eq!(resolver.resolve(ip("10.0.0.1"), &headers), ip("10.0.0.2"));
+        headers.remove(FORWARDED);
+        assert_eq!(resolver.resolve(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+
+        // nothing is trusted without trusted proxies
+        let resolver = ClientIpResolver::new(Vec::new(), ForwardedHeader::XForwardedFor);
+        assert_eq!(resolver.resolve(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+    }
+}
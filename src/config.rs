@@ -1,18 +1,127 @@
-use std::{fs::read_to_string, net::IpAddr, path::PathBuf};
+use std::{
+    collections::BTreeMap, fmt, fs::read_to_string, net::IpAddr, num::NonZeroUsize, path::PathBuf,
+};
 
-use clap::Parser;
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use ipnet::IpNet;
 use log::LevelFilter;
-use serde::Deserialize;
+use schemars::{JsonSchema, Schema};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use url::Url;
 
+use crate::cli::Command;
+
 fn default_url() -> Url {
     Url::parse("http://localhost:8080").unwrap()
 }
 
-#[derive(Parser, Debug, Deserialize)]
+/// Origin of a configuration option value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    File,
+    Env,
+    Cli,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match self {
+            Self::Default => "default",
+            Self::File => "config file",
+            Self::Env => "environment",
+            Self::Cli => "command line",
+        };
+        f.write_str(source)
+    }
+}
+
+/// TLS protocol version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize, Serialize, JsonSchema)]
+pub enum TlsVersion {
+    #[value(name = "1.2")]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// gRPC message compression algorithm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum GrpcCompression {
+    Gzip,
+    Zstd,
+}
+
+/// Forwarding header reporting client address, set by trusted reverse proxies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For`
+    XForwardedFor,
+    /// `Forwarded` (RFC 7239)
+    Forwarded,
+}
+
+/// Policy of choosing Defguard Core instance requests are sent to, when more than one is connected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum CoreSelection {
+    /// Core connected first, the next one takes over when it disconnects
+    Primary,
+    /// Core connected most recently
+    MostRecent,
+    /// Each connected core in turn
+    RoundRobin,
+}
+
+/// Protocol of OpenTelemetry exporter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum OtlpProtocol {
+    /// OTLP over gRPC, usually on port 4317
+    Grpc,
+    /// OTLP with protobuf payload over HTTP, usually on port 4318
+    HttpProtobuf,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Rate limiting policy, applied to its routes instead of the global rate limit.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub struct RateLimitPolicy {
+    /// Path prefixes of routes the policy applies to, e.g. "/api/v1/password-reset"
+    pub routes: Vec<String>,
+    /// Replenish rate in seconds, 0 disables rate limiting of the routes
+    pub per_second: u64,
+    /// Burst size, 0 disables rate limiting of the routes
+    pub burst: u32,
+    /// Limit requests per client IP address
+    #[serde(default = "default_true")]
+    pub by_ip: bool,
+    /// Fields of JSON request body to limit requests by, e.g. "email", "token" or "pubkey";
+    /// requests without any of them are limited by client IP address
+    #[serde(default)]
+    pub by_fields: Vec<String>,
+    /// Client networks the policy doesn't apply to
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub exempt_cidrs: Vec<IpNet>,
+}
+
+// Options holding secrets, their values are never printed.
+const SECRET_OPTIONS: &[&str] = &["cookie_key", "cookie_previous_keys", "grpc_token"];
+
+#[derive(Parser, Deserialize, Serialize, JsonSchema)]
 #[command(version)]
+#[schemars(transform = all_optional)]
 pub struct Config {
-    // port the API server will listen on
+    /// Port the API server will listen on
     #[arg(
         long,
         short = 'p',
@@ -21,25 +130,260 @@ pub struct Config {
     )]
     pub http_port: u16,
 
-    // port the API server will listen on
+    /// Port the gRPC server will listen on
     #[arg(long, env = "DEFGUARD_PROXY_GRPC_PORT", default_value_t = 50051)]
     pub grpc_port: u16,
 
+    /// Serve gRPC on the API server port instead of grpc_port, requests are routed by their
+    /// content type. The API server TLS configuration is used, gRPC certificates can't be set
+    #[arg(long, env = "DEFGUARD_PROXY_GRPC_SINGLE_PORT")]
+    #[serde(default)]
+    pub grpc_single_port: bool,
+
+    /// Enable gRPC server reflection service
+    #[arg(long, env = "DEFGUARD_PROXY_GRPC_REFLECTION")]
+    #[serde(default)]
+    pub grpc_reflection: bool,
+
+    /// Path to gRPC TLS certificate file
     #[arg(long, env = "DEFGUARD_PROXY_GRPC_CERT")]
     pub grpc_cert: Option<String>,
 
+    /// Path to gRPC TLS key file
     #[arg(long, env = "DEFGUARD_PROXY_GRPC_KEY")]
     pub grpc_key: Option<String>,
 
+    /// Path to CA certificates file, enables verification of Defguard Core client certificates
+    #[arg(long, env = "DEFGUARD_PROXY_GRPC_CLIENT_CA")]
+    pub grpc_client_ca: Option<String>,
+
+    /// Pattern client certificate common name or alternative name must match, `*` matches any
+    /// sequence of characters
+    #[arg(long, env = "DEFGUARD_PROXY_GRPC_CLIENT_ALLOWED_NAME")]
+    pub grpc_client_allowed_name: Option<String>,
+
+    /// Source networks allowed to connect to the gRPC server, all are allowed if empty
+    #[arg(long, env = "DEFGUARD_PROXY_GRPC_ALLOWED_CIDRS", value_delimiter = ',')]
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub grpc_allowed_cidrs: Vec<IpNet>,
+
+    /// Networks of reverse proxies trusted to report client address in `trusted_proxy_header`,
+    /// the header is ignored if empty and the TCP peer address is used as client address
+    #[arg(long, env = "DEFGUARD_PROXY_TRUSTED_PROXIES", value_delimiter = ',')]
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// Header in which trusted proxies report client address, other forwarding headers are
+    /// ignored
+    #[arg(
+        long,
+        env = "DEFGUARD_PROXY_TRUSTED_PROXY_HEADER",
+        value_enum,
+        default_value = "x-forwarded-for"
+    )]
+    pub trusted_proxy_header: ForwardedHeader,
+
+    /// Expect PROXY protocol (version 1 or 2) header on connections to the API web server and
+    /// HTTP redirect server, the client address it carries replaces the TCP peer address.
+    /// Only peers in trusted_proxies may connect
+    #[arg(long, env = "DEFGUARD_PROXY_HTTP_PROXY_PROTOCOL")]
+    #[serde(default)]
+    pub http_proxy_protocol: bool,
+
+    /// Expect PROXY protocol (version 1 or 2) header on connections to the gRPC server.
+    /// Only peers in trusted_proxies may connect
+    #[arg(long, env = "DEFGUARD_PROXY_GRPC_PROXY_PROTOCOL")]
+    #[serde(default)]
+    pub grpc_proxy_protocol: bool,
+
+    /// Shared secret Defguard Core must send as a bearer token in gRPC `authorization` metadata
+    #[arg(long, env = "DEFGUARD_PROXY_GRPC_TOKEN")]
+    pub grpc_token: Option<String>,
+
+    /// Time in seconds requests are held while no Defguard Core is connected, waiting for it to
+    /// reconnect, 0 fails them right away
+    #[arg(long, env = "DEFGUARD_PROXY_CORE_RECONNECT_GRACE", default_value_t = 0)]
+    pub core_reconnect_grace: u64,
+
+    /// Maximum number of requests held while waiting for Defguard Core to reconnect
+    #[arg(
+        long,
+        env = "DEFGUARD_PROXY_CORE_RECONNECT_MAX_REQUESTS",
+        default_value_t = 100
+    )]
+    pub core_reconnect_max_requests: usize,
+
+    /// Compression of messages sent to Defguard Core, used if Core accepts it
+    #[arg(long, env = "DEFGUARD_PROXY_GRPC_SEND_COMPRESSION", value_enum)]
+    pub grpc_send_compression: Option<GrpcCompression>,
+
+    /// Compression algorithms accepted in messages from Defguard Core
+    #[arg(
+        long,
+        env = "DEFGUARD_PROXY_GRPC_ACCEPT_COMPRESSION",
+        value_enum,
+        value_delimiter = ','
+    )]
+    #[serde(default)]
+    pub grpc_accept_compression: Vec<GrpcCompression>,
+
+    /// Maximum size in bytes of a message sent to Defguard Core
+    #[arg(
+        long,
+        env = "DEFGUARD_PROXY_GRPC_MAX_ENCODING_MESSAGE_SIZE",
+        default_value_t = 4 * 1024 * 1024
+    )]
+    pub grpc_max_encoding_message_size: usize,
+
+    /// Maximum size in bytes of a message received from Defguard Core
+    #[arg(
+        long,
+        env = "DEFGUARD_PROXY_GRPC_MAX_DECODING_MESSAGE_SIZE",
+        default_value_t = 4 * 1024 * 1024
+    )]
+    pub grpc_max_decoding_message_size: usize,
+
+    /// Interval in seconds of HTTP/2 keepalive pings sent to Defguard Core, 0 disables them
+    #[arg(
+        long,
+        env = "DEFGUARD_PROXY_GRPC_KEEPALIVE_INTERVAL",
+        default_value_t = 30
+    )]
+    pub grpc_keepalive_interval: u64,
+
+    /// Time in seconds to wait for keepalive ping acknowledgement before closing the connection
+    #[arg(
+        long,
+        env = "DEFGUARD_PROXY_GRPC_KEEPALIVE_TIMEOUT",
+        default_value_t = 20
+    )]
+    pub grpc_keepalive_timeout: u64,
+
+    /// Time in seconds after which Defguard Core which doesn't respond to pending requests is
+    /// considered stale and disconnected, 0 disables the check
+    #[arg(long, env = "DEFGUARD_PROXY_CORE_STALE_TIMEOUT", default_value_t = 30)]
+    pub core_stale_timeout: u64,
+
+    /// Defguard Core instance selection policy
+    #[arg(
+        long,
+        env = "DEFGUARD_PROXY_CORE_SELECTION",
+        value_enum,
+        default_value = "primary"
+    )]
+    pub core_selection: CoreSelection,
+
+    /// Maximum number of requests queued for a single Defguard Core instance, further requests
+    /// are rejected until the queue drains
+    #[arg(long, env = "DEFGUARD_PROXY_CORE_QUEUE_SIZE", default_value = "1024")]
+    pub core_queue_size: NonZeroUsize,
+
+    /// URL of Defguard Core to connect to, enables outbound mode in which the proxy is a gRPC
+    /// client of Core opening the `CoreProxy.Bidi` stream; the gRPC port is not bound in this mode.
+    /// Not supported yet, Defguard Core doesn't serve this stream
+    #[arg(long, env = "DEFGUARD_PROXY_CORE_URL")]
+    pub core_url: Option<Url>,
+
+    /// Path to CA certificates file used to verify Defguard Core in outbound mode, system
+    /// certificates are used if not set
+    #[arg(long, env = "DEFGUARD_PROXY_CORE_CA")]
+    pub core_ca: Option<String>,
+
+    /// Path to HTTPS certificate file, enables TLS on the API server
+    #[arg(long, env = "DEFGUARD_PROXY_HTTP_CERT")]
+    pub http_cert: Option<String>,
+
+    /// Path to HTTPS key file
+    #[arg(long, env = "DEFGUARD_PROXY_HTTP_KEY")]
+    pub http_key: Option<String>,
+
+    /// Minimum TLS version accepted by the API server
+    #[arg(
+        long,
+        env = "DEFGUARD_PROXY_HTTP_TLS_MIN_VERSION",
+        value_enum,
+        default_value = "1.2"
+    )]
+    pub http_tls_min_version: TlsVersion,
+
+    /// Time in seconds to let in-flight requests finish on shutdown, after which remaining
+    /// connections are dropped
+    #[arg(long, env = "DEFGUARD_PROXY_SHUTDOWN_TIMEOUT", default_value_t = 30)]
+    pub shutdown_timeout: u64,
+
+    /// Port of plain HTTP server redirecting to HTTPS, disabled if not set
+    #[arg(long, env = "DEFGUARD_PROXY_HTTP_REDIRECT_PORT")]
+    pub http_redirect_port: Option<u16>,
+
+    /// Log level
     #[arg(long, env = "DEFGUARD_PROXY_LOG_LEVEL", default_value_t = LevelFilter::Info)]
+    #[schemars(with = "String")]
     pub log_level: LevelFilter,
 
+    /// OpenTelemetry collector endpoint, enables export of traces and metrics over OTLP
+    #[arg(long, env = "DEFGUARD_PROXY_OTLP_ENDPOINT", value_parser = Url::parse)]
+    pub otlp_endpoint: Option<Url>,
+
+    /// Protocol used to export traces and metrics to OpenTelemetry collector
+    #[arg(
+        long,
+        env = "DEFGUARD_PROXY_OTLP_PROTOCOL",
+        value_enum,
+        default_value = "grpc"
+    )]
+    pub otlp_protocol: OtlpProtocol,
+
+    /// Fraction of traces sampled, between 0 and 1. Requests which belong to a trace started
+    /// upstream follow the upstream sampling decision.
+    #[arg(
+        long,
+        env = "DEFGUARD_PROXY_OTLP_SAMPLING_RATIO",
+        default_value_t = 1.0
+    )]
+    pub otlp_sampling_ratio: f64,
+
+    /// Expose Prometheus metrics at /metrics
+    #[arg(long, env = "DEFGUARD_PROXY_METRICS")]
+    #[serde(default)]
+    pub metrics: bool,
+
+    /// Port of a separate server exposing /metrics, the API server exposes them if not set
+    #[arg(long, env = "DEFGUARD_PROXY_METRICS_PORT")]
+    pub metrics_port: Option<u16>,
+
+    /// Address the metrics server will bind to
+    #[arg(long, env = "DEFGUARD_PROXY_METRICS_BIND_ADDRESS")]
+    pub metrics_bind_address: Option<IpAddr>,
+
+    /// Rate limiter replenish rate in seconds, 0 disables rate limiting
     #[arg(long, env = "DEFGUARD_PROXY_RATELIMIT_PERSECOND", default_value_t = 0)]
     pub rate_limit_per_second: u64,
 
+    /// Rate limiter burst size, 0 disables rate limiting
     #[arg(long, env = "DEFGUARD_PROXY_RATELIMIT_BURST", default_value_t = 0)]
     pub rate_limit_burst: u32,
 
+    /// Named rate limiting policies for groups of routes, can only be set in the config file
+    #[arg(skip)]
+    #[serde(default)]
+    pub rate_limit_policies: BTreeMap<String, RateLimitPolicy>,
+
+    /// Number of failed authentication attempts on MFA, enrollment and OpenID callback routes
+    /// after which the client IP address or token is locked out, 0 disables the lockout
+    #[arg(long, env = "DEFGUARD_PROXY_LOCKOUT_THRESHOLD", default_value_t = 0)]
+    pub lockout_threshold: u32,
+
+    /// Time in seconds failed authentication attempts are counted within
+    #[arg(long, env = "DEFGUARD_PROXY_LOCKOUT_WINDOW", default_value_t = 600)]
+    pub lockout_window: u64,
+
+    /// Time in seconds a client IP address or token stays locked out
+    #[arg(long, env = "DEFGUARD_PROXY_LOCKOUT_DURATION", default_value_t = 900)]
+    pub lockout_duration: u64,
+
+    /// Public URL of the proxy
     #[arg(
         long,
         env = "DEFGUARD_PROXY_URL",
@@ -54,32 +398,268 @@ pub struct Config {
     #[serde(skip)]
     config_path: Option<PathBuf>,
 
+    /// Address the API server will bind to
     #[arg(long, env = "DEFGUARD_HTTP_BIND_ADDRESS")]
     pub http_bind_address: Option<IpAddr>,
 
+    /// Address the gRPC server will bind to
     #[arg(long, env = "DEFGUARD_GRPC_BIND_ADDRESS")]
     pub grpc_bind_address: Option<IpAddr>,
+
+    /// Base64 encoded key (at least 64 bytes) used to encrypt cookies
+    #[arg(long, env = "DEFGUARD_PROXY_COOKIE_KEY")]
+    pub cookie_key: Option<String>,
+
+    /// Path to file holding base64 encoded cookie encryption key, generated if it doesn't exist
+    #[arg(long, env = "DEFGUARD_PROXY_COOKIE_KEY_FILE")]
+    pub cookie_key_file: Option<String>,
+
+    /// Previous cookie encryption keys, still accepted when decrypting cookies
+    #[arg(
+        long,
+        env = "DEFGUARD_PROXY_COOKIE_PREVIOUS_KEYS",
+        value_delimiter = ','
+    )]
+    #[serde(default)]
+    pub cookie_previous_keys: Vec<String>,
+
+    #[command(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+
+    /// Source of each option value
+    #[arg(skip)]
+    #[serde(skip)]
+    sources: BTreeMap<String, ConfigSource>,
+
+    /// Problems found while reading the config file, logged by `log_sources`
+    #[arg(skip)]
+    #[serde(skip)]
+    warnings: Vec<String>,
+}
+
+// All options have defaults, so none of them is required in the config file.
+fn all_optional(schema: &mut Schema) {
+    schema.remove("required");
+}
+
+impl Config {
+    /// Returns effective option values along with their sources. Secrets are redacted.
+    #[must_use]
+    pub fn effective_values(&self) -> Vec<(String, Value, ConfigSource)> {
+        let Ok(Value::Object(values)) = serde_json::to_value(self) else {
+            return Vec::new();
+        };
+        values
+            .into_iter()
+            .map(|(name, value)| {
+                let source = self
+                    .sources
+                    .get(&name)
+                    .copied()
+                    .unwrap_or(ConfigSource::Default);
+                let is_empty = value.is_null() || value.as_array().is_some_and(Vec::is_empty);
+                let value = if SECRET_OPTIONS.contains(&name.as_str()) && !is_empty {
+                    Value::String("<redacted>".into())
+                } else {
+                    value
+                };
+                (name, value, source)
+            })
+            .collect()
+    }
+
+    /// Log effective configuration, noting where each value came from, and problems found
+    /// while reading the config file. Secrets are redacted.
+    pub fn log_sources(&self) {
+        if let Some(config_path) = &self.config_path {
+            info!("Read configuration from file: {config_path:?}");
+        }
+        for warning in &self.warnings {
+            warn!("{warning}");
+        }
+        info!("Effective configuration:");
+        for (name, value, source) in self.effective_values() {
+            info!("  {name} = {value} ({source})");
+        }
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// Parse `args` ignoring environment variables, so tests don't depend on the environment.
+    pub(crate) fn parse_without_env<I, T>(args: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let matches = Config::command()
+            .mut_args(|arg| arg.env(None))
+            .get_matches_from(args);
+        Config::from_arg_matches(&matches).unwrap()
+    }
+}
+
+// Secrets are redacted, like in `effective_values`.
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Config");
+        for (name, value, _) in self.effective_values() {
+            debug.field(&name, &format_args!("{value}"));
+        }
+        debug.finish_non_exhaustive()
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum ConfigError {
     #[error("Failed to read config file")]
     IoError(#[from] std::io::Error),
-    #[error("Failed to parse config file")]
+    #[error("Failed to parse config file: {0}")]
     ParseError(#[from] toml::de::Error),
+    #[error("Invalid configuration value: {0}")]
+    InvalidValue(#[from] serde_json::Error),
 }
 
+/// Build configuration from the config file, environment variables and CLI arguments.
+/// Values are merged in that order, so CLI arguments take precedence over environment variables,
+/// which take precedence over the config file.
 pub fn get_config() -> Result<Config, ConfigError> {
-    // parse CLI arguments to get config file path
-    let cli_config = Config::parse();
+    let matches = Config::command().get_matches();
+    let config = Config::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
 
     // load config from file if one was specified
-    if let Some(config_path) = cli_config.config_path {
-        info!("Reading configuration from file: {config_path:?}");
-        let config_toml = read_to_string(config_path)?;
-        let file_config: Config = toml::from_str(&config_toml)?;
-        Ok(file_config)
+    let file_config = if let Some(config_path) = &config.config_path {
+        Some(toml::from_str(&read_to_string(config_path)?)?)
     } else {
-        Ok(cli_config)
+        None
+    };
+
+    merge_config(config, &matches, file_config)
+}
+
+// Overrides values which were not explicitly set in env or CLI with ones from the config file.
+// Tracing isn't initialized yet, so problems are returned in `Config::warnings`.
+fn merge_config(
+    config: Config,
+    matches: &ArgMatches,
+    file_config: Option<toml::Table>,
+) -> Result<Config, ConfigError> {
+    let Value::Object(mut values) = serde_json::to_value(&config)? else {
+        unreachable!("Config always serializes to an object");
+    };
+    let mut sources = BTreeMap::new();
+    let mut warnings = Vec::new();
+    for name in values.keys() {
+        // options which can only be set in the config file are not arguments
+        let source = match matches
+            .try_contains_id(name)
+            .ok()
+            .and_then(|_| matches.value_source(name))
+        {
+            Some(ValueSource::CommandLine) => ConfigSource::Cli,
+            Some(ValueSource::EnvVariable) => ConfigSource::Env,
+            _ => ConfigSource::Default,
+        };
+        sources.insert(name.clone(), source);
+    }
+
+    for (name, value) in file_config.unwrap_or_default() {
+        match sources.get_mut(&name) {
+            Some(source) if *source == ConfigSource::Default => {
+                values.insert(name, serde_json::to_value(value)?);
+                *source = ConfigSource::File;
+            }
+            Some(_) => (),
+            None => warnings.push(format!("Ignoring unknown configuration option: {name}")),
+        }
+    }
+
+    let mut merged: Config = serde_json::from_value(Value::Object(values))?;
+    merged.config_path = config.config_path;
+    merged.command = config.command;
+    merged.sources = sources;
+    merged.warnings = warnings;
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_from(args: &[&str], file: &str) -> Config {
+        config_with_env(args, &[], file)
+    }
+
+    // Each `(option, variable, value)` in `env` reads the option from a test-specific variable,
+    // other options ignore the environment so values set in the process don't leak in.
+    fn config_with_env(args: &[&str], env: &[(&str, &'static str, &str)], file: &str) -> Config {
+        let mut command = Config::command().mut_args(|arg| arg.env(None));
+        for (name, var, value) in env {
+            std::env::set_var(var, value);
+            command = command.mut_arg(*name, |arg| arg.env(*var));
+        }
+        let matches = command.get_matches_from(args);
+        let config = Config::from_arg_matches(&matches).unwrap();
+        merge_config(config, &matches, Some(toml::from_str(file).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_config_precedence() {
+        let config = config_from(
+            &["defguard-proxy", "--grpc-port", "50052"],
+            "grpc_port = 50053\nhttp_port = 8081\nlog_level = \"debug\"",
+        );
+        // CLI wins over config file
+        assert_eq!(config.grpc_port, 50052);
+        assert_eq!(config.sources["grpc_port"], ConfigSource::Cli);
+        assert!(config.warnings.is_empty());
+        // config file wins over defaults
+        assert_eq!(config.http_port, 8081);
+        assert_eq!(config.sources["http_port"], ConfigSource::File);
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        // missing keys use defaults
+        assert_eq!(config.rate_limit_burst, 0);
+        assert_eq!(config.url, default_url());
+        assert_eq!(config.sources["url"], ConfigSource::Default);
+
+        // environment wins over config file, CLI wins over environment
+        let config = config_with_env(
+            &["defguard-proxy", "--grpc-port", "50052"],
+            &[
+                ("http_port", "DEFGUARD_PROXY_TEST_HTTP_PORT", "8082"),
+                ("grpc_port", "DEFGUARD_PROXY_TEST_GRPC_PORT", "50054"),
+            ],
+            "grpc_port = 50053\nhttp_port = 8081\nunknown_option = 1",
+        );
+        assert_eq!(config.http_port, 8082);
+        assert_eq!(config.sources["http_port"], ConfigSource::Env);
+        assert_eq!(config.grpc_port, 50052);
+        assert_eq!(config.sources["grpc_port"], ConfigSource::Cli);
+        // unknown options are reported
+        assert_eq!(
+            config.warnings,
+            ["Ignoring unknown configuration option: unknown_option"]
+        );
+
+        // options set only in the config file
+        let config = config_from(
+            &["defguard-proxy"],
+            "[rate_limit_policies.poll]\nroutes = [\"/api/v1/poll\"]\nper_second = 1\nburst = 30",
+        );
+        assert_eq!(config.sources["rate_limit_policies"], ConfigSource::File);
+        let policy = &config.rate_limit_policies["poll"];
+        assert_eq!(policy.burst, 30);
+        assert!(policy.by_ip);
+        assert!(policy.by_fields.is_empty());
+
+        // secrets are not printed
+        let config = config_from(
+            &["defguard-proxy", "--grpc-token", "secret-token"],
+            "cookie_previous_keys = [\"secret-key\"]",
+        );
+        let debug = format!("{config:?}");
+        assert!(debug.contains("grpc_token: \"<redacted>\""));
+        assert!(!debug.contains("secret"));
     }
 }
@@ -0,0 +1,198 @@
+use std::{
+    fs::{read_to_string, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::Arc,
+};
+
+use axum::{
+    body::Body,
+    extract::{FromRef, Request, State},
+    http::{header::COOKIE, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use axum_extra::extract::cookie::{Cookie, Key};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use cookie::CookieJar;
+
+use crate::config::Config;
+
+#[derive(thiserror::Error, Debug)]
+pub enum CookieKeyError {
+    #[error("Failed to read or write cookie key file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Cookie key is not valid base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("Cookie key too short, it must be at least 64 bytes long")]
+    TooShort,
+}
+
+/// Keys used to encrypt private cookies. Cookies are always encrypted with the current key,
+/// previous keys are only used to decrypt cookies issued before the key was rotated.
+#[derive(Clone)]
+pub(crate) struct CookieKeys {
+    current: Key,
+    previous: Arc<[Key]>,
+}
+
+impl CookieKeys {
+    /// Load cookie keys from configuration. The key is taken from `cookie_key`, or read from
+    /// `cookie_key_file`, which is created with a random key if it doesn't exist.
+    /// If neither is set, a random key is generated, so cookies won't survive a restart.
+    pub(crate) fn from_config(config: &Config) -> Result<Self, CookieKeyError> {
+        let current = if let Some(key) = &config.cookie_key {
+            parse_key(key)?
+        } else if let Some(path) = &config.cookie_key_file {
+            load_or_create_key(Path::new(path))?
+        } else {
+            warn!(
+                "Neither cookie_key nor cookie_key_file is set, using a random cookie key. \
+                Cookies will be invalidated on restart and won't be shared between proxy instances."
+            );
+            Key::generate()
+        };
+        let previous = config
+            .cookie_previous_keys
+            .iter()
+            .map(|key| parse_key(key))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { current, previous })
+    }
+}
+
+impl FromRef<CookieKeys> for Key {
+    fn from_ref(keys: &CookieKeys) -> Self {
+        keys.current.clone()
+    }
+}
+
+/// Parse base64 encoded cookie key.
+pub(crate) fn parse_key(key: &str) -> Result<Key, CookieKeyError> {
+    let key = BASE64_STANDARD.decode(key.trim())?;
+    Key::try_from(key.as_slice()).map_err(|_| CookieKeyError::TooShort)
+}
+
+// Read key from the file, or generate a new one and store it if the file doesn't exist.
+fn load_or_create_key(path: &Path) -> Result<Key, CookieKeyError> {
+    match read_to_string(path) {
+        Ok(key) => parse_key(&key),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            info!("Cookie key file {path:?} not found, generating a new key");
+            let key = Key::generate();
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)?;
+            writeln!(file, "{}", BASE64_STANDARD.encode(key.master()))?;
+            Ok(key)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Re-encrypt request cookies issued with one of the previous keys using the current key,
+/// so `PrivateCookieJar` extractors in handlers can read them.
+pub(crate) async fn reencrypt_cookies_middleware(
+    State(keys): State<CookieKeys>,
+    mut request: Request,
+    next: Next,
+) -> Response<Body> {
+    if !keys.previous.is_empty() {
+        reencrypt_cookies(&keys, &mut request);
+    }
+    next.run(request).await
+}
+
+fn reencrypt_cookies(keys: &CookieKeys, request: &mut Request) {
+    let empty_jar = CookieJar::new();
+    let mut jar = CookieJar::new();
+    let mut changed = false;
+    let cookies = request
+        .headers()
+        .get_all(COOKIE)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| Cookie::parse_encoded(cookie.trim().to_owned()).ok());
+    for cookie in cookies {
+        if empty_jar
+            .private(&keys.current)
+            .decrypt(cookie.clone())
+            .is_none()
+        {
+            let decrypted = keys
+                .previous
+                .iter()
+                .find_map(|key| empty_jar.private(key).decrypt(cookie.clone()));
+            if let Some(decrypted) = decrypted {
+                debug!("Cookie {} was encrypted with a previous key", cookie.name());
+                jar.private_mut(&keys.current).add_original(decrypted);
+                changed = true;
+                continue;
+            }
+        }
+        jar.add_original(cookie);
+    }
+
+    if changed {
+        let header = jar
+            .iter()
+            .map(|cookie| cookie.stripped().encoded().to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+        if let Ok(value) = HeaderValue::from_str(&header) {
+            request.headers_mut().insert(COOKIE, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, os::unix::fs::PermissionsExt};
+
+    use axum_extra::extract::PrivateCookieJar;
+
+    use super::*;
+
+    #[test]
+    fn test_load_or_create_key() {
+        let path = env::temp_dir().join(format!("defguard-proxy-cookie-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let key = load_or_create_key(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // existing file is read, not overwritten
+        assert_eq!(load_or_create_key(&path).unwrap().master(), key.master());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reencrypt_cookie_from_previous_key() {
+        let old_key = Key::generate();
+        let keys = CookieKeys {
+            current: Key::generate(),
+            previous: Arc::from([old_key.clone()]),
+        };
+        let mut old_jar = CookieJar::new();
+        old_jar
+            .private_mut(&old_key)
+            .add(Cookie::new("session", "secret"));
+        let encrypted = old_jar.get("session").unwrap().encoded().to_string();
+
+        let mut request = Request::builder()
+            .header(COOKIE, format!("{encrypted}; plain=value"))
+            .body(Body::empty())
+            .unwrap();
+        reencrypt_cookies(&keys, &mut request);
+
+        let jar = PrivateCookieJar::from_headers(request.headers(), keys.current.clone());
+        assert_eq!(jar.get("session").unwrap().value(), "secret");
+        let header = request.headers()[COOKIE].to_str().unwrap();
+        assert!(header.contains("plain=value"));
+    }
+}
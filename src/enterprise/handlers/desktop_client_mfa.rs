@@ -85,10 +85,13 @@ pub(super) async fn mfa_auth_callback(
 
     debug!("Sending MFA OIDC authenticate request to core service");
 
-    let rx = state.grpc_server.send(
-        core_request::Payload::ClientMfaOidcAuthenticate(request),
-        device_info,
-    )?;
+    let rx = state
+        .grpc_server
+        .send(
+            core_request::Payload::ClientMfaOidcAuthenticate(request),
+            device_info,
+        )
+        .await?;
 
     let payload = get_core_response(rx).await?;
 
@@ -75,7 +75,8 @@ async fn auth_info(
 
     let rx = state
         .grpc_server
-        .send(core_request::Payload::AuthInfo(request), device_info)?;
+        .send(core_request::Payload::AuthInfo(request), device_info)
+        .await?;
     let payload = get_core_response(rx).await?;
     if let core_response::Payload::AuthInfo(response) = payload {
         debug!("Received auth info response");
@@ -163,7 +164,8 @@ async fn auth_callback(
 
     let rx = state
         .grpc_server
-        .send(core_request::Payload::AuthCallback(request), device_info)?;
+        .send(core_request::Payload::AuthCallback(request), device_info)
+        .await?;
     let payload = get_core_response(rx).await?;
 
     if let core_response::Payload::AuthCallback(AuthCallbackResponse { url, token }) = payload {
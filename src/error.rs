@@ -6,7 +6,7 @@ use axum::{
 use serde_json::json;
 use tonic::{metadata::errors::InvalidMetadataValue, Code, Status};
 
-use crate::proto::CoreError;
+use crate::{lockout::AuthFailure, proto::CoreError};
 
 #[derive(thiserror::Error, Debug)]
 pub enum ApiError {
@@ -20,6 +20,12 @@ pub enum ApiError {
     BadRequest(String),
     #[error("Core gRPC response timeout")]
     CoreTimeout,
+    #[error("Core disconnected before sending gRPC response")]
+    CoreDisconnected,
+    #[error("Core request queue is full")]
+    CoreBusy,
+    #[error("Core is not connected")]
+    CoreNotConnected,
     #[error("Invalid core gRPC response type received")]
     InvalidResponseType,
     #[error("Permission denied: {0}")]
@@ -35,6 +41,7 @@ pub enum ApiError {
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
         error!("{self}");
+        let auth_failure = matches!(self, Self::Unauthorized(_) | Self::PermissionDenied(_));
         let (status, error_message) = match self {
             Self::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg),
             Self::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
@@ -45,6 +52,14 @@ impl IntoResponse for ApiError {
             ),
             Self::PreconditionRequired(msg) => (StatusCode::PRECONDITION_REQUIRED, msg),
             Self::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
+            Self::CoreDisconnected | Self::CoreNotConnected => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Defguard Core is not available".to_string(),
+            ),
+            Self::CoreBusy => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Defguard Core is busy, try again later".to_string(),
+            ),
             _ => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Internal server error".to_string(),
@@ -53,7 +68,11 @@ impl IntoResponse for ApiError {
 
         let body = Json(json!({"error": error_message}));
 
-        (status, body).into_response()
+        let mut response = (status, body).into_response();
+        if auth_failure {
+            response.extensions_mut().insert(AuthFailure);
+        }
+        response
     }
 }
 
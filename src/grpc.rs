@@ -1,78 +1,528 @@
 use std::{
-    any::Any,
-    collections::HashMap,
+    collections::BTreeMap,
     net::SocketAddr,
+    num::NonZeroUsize,
     sync::{
-        atomic::{AtomicBool, AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc, Mutex,
     },
+    time::{Duration, Instant},
 };
 
 use defguard_version::{get_tracing_variables, ComponentInfo, DefguardComponent, Version};
-use tokio::sync::{mpsc, oneshot};
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use tonic::{Request, Response, Status, Streaming};
+use opentelemetry::{global, KeyValue};
+use prost::Message;
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot, Notify,
+    },
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    codec::CompressionEncoding, server::NamedService, Code, Request, Response, Status, Streaming,
+};
+use tonic_health::{server::HealthReporter, ServingStatus};
 use tracing::Instrument;
 
 use crate::{
+    config::{CoreSelection, GrpcCompression},
     error::ApiError,
+    handlers::CORE_RESPONSE_TIMEOUT,
+    metrics::core_request_name,
+    pending::PendingRequests,
     proto::{core_request, core_response, proxy_server, CoreRequest, CoreResponse, DeviceInfo},
+    tls::cert_names,
 };
 
-// connected clients
-type ClientMap = HashMap<SocketAddr, mpsc::UnboundedSender<Result<CoreRequest, Status>>>;
+// How often connected cores are checked for staleness.
+const STALE_CHECK_PERIOD: Duration = Duration::from_secs(1);
+// How often gRPC health status is updated.
+const HEALTH_UPDATE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Sends requests to a connected core.
+pub(crate) type RequestSender = mpsc::Sender<Result<CoreRequest, Status>>;
+
+/// Connected Defguard Core instance.
+struct CoreClient {
+    address: SocketAddr,
+    version: Version,
+    connected_at: Instant,
+    tx: RequestSender,
+    // When the first request since the last message from the core was sent.
+    awaiting_since: Arc<Mutex<Option<Instant>>>,
+    // Dropped along with the client, which stops reading the core's stream.
+    _removed: oneshot::Sender<()>,
+}
+
+impl From<GrpcCompression> for CompressionEncoding {
+    fn from(compression: GrpcCompression) -> Self {
+        match compression {
+            GrpcCompression::Gzip => Self::Gzip,
+            GrpcCompression::Zstd => Self::Zstd,
+        }
+    }
+}
+
+// connected clients, keyed by connection sequence number, so they are ordered by connection time
+type ClientMap = BTreeMap<u64, CoreClient>;
+
+/// Outcome of routing a request to connected cores.
+enum Routed {
+    Sent(oneshot::Receiver<core_response::Payload>),
+    Busy,
+    NotConnected(CoreRequest),
+}
+
+// Counts requests held until a core reconnects, the count is decremented on drop.
+struct HeldRequest<'a>(&'a AtomicUsize);
+
+impl<'a> HeldRequest<'a> {
+    fn acquire(held: &'a AtomicUsize, max: usize) -> Option<Self> {
+        held.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+            (count < max).then_some(count + 1)
+        })
+        .ok()
+        .map(|_| Self(held))
+    }
+}
+
+impl Drop for HeldRequest<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
 pub(crate) struct ProxyServer {
     current_id: Arc<AtomicU64>,
+    next_client_id: Arc<AtomicU64>,
     clients: Arc<Mutex<ClientMap>>,
-    results: Arc<Mutex<HashMap<u64, oneshot::Sender<core_response::Payload>>>>,
+    pending: Arc<PendingRequests>,
     pub(crate) connected: Arc<AtomicBool>,
-    pub(crate) core_version: Arc<Mutex<Option<Version>>>,
+    selection: CoreSelection,
+    round_robin: Arc<AtomicUsize>,
+    queue_size: NonZeroUsize,
+    reconnected: Arc<Notify>,
+    reconnect_grace: Duration,
+    max_held: usize,
+    held: Arc<AtomicUsize>,
+    max_message_size: usize,
 }
 
 impl ProxyServer {
     #[must_use]
-    /// Create new `ProxyServer`.
-    pub(crate) fn new() -> Self {
+    /// Create new `ProxyServer`, routing requests to connected cores according to `selection`.
+    /// At most `queue_size` requests are queued for each core.
+    pub(crate) fn new(selection: CoreSelection, queue_size: NonZeroUsize) -> Self {
         Self {
             current_id: Arc::new(AtomicU64::new(1)),
-            clients: Arc::new(Mutex::new(HashMap::new())),
-            results: Arc::new(Mutex::new(HashMap::new())),
+            next_client_id: Arc::new(AtomicU64::new(1)),
+            clients: Arc::new(Mutex::new(BTreeMap::new())),
+            pending: Arc::new(PendingRequests::new(CORE_RESPONSE_TIMEOUT)),
             connected: Arc::new(AtomicBool::new(false)),
-            core_version: Arc::new(Mutex::new(None)),
+            selection,
+            round_robin: Arc::new(AtomicUsize::new(0)),
+            queue_size,
+            reconnected: Arc::new(Notify::new()),
+            reconnect_grace: Duration::ZERO,
+            max_held: 0,
+            held: Arc::new(AtomicUsize::new(0)),
+            max_message_size: usize::MAX,
+        }
+    }
+
+    /// Reject requests which encode to messages larger than `max_message_size` bytes.
+    #[must_use]
+    pub(crate) fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Hold up to `max_held` requests arriving while no core is connected, for at most `grace`,
+    /// instead of failing them right away.
+    #[must_use]
+    pub(crate) fn with_reconnect_grace(mut self, grace: Duration, max_held: usize) -> Self {
+        self.reconnect_grace = grace;
+        self.max_held = max_held;
+        self
+    }
+
+    /// Add newly connected core, returns its key. Requests held for reconnection are routed.
+    fn add_client(&self, client: CoreClient) -> u64 {
+        let client_id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut clients = self.clients.lock().unwrap();
+            clients.insert(client_id, client);
+            info!("Connected Defguard Core instances: {}", clients.len());
+        }
+        self.connected.store(true, Ordering::Relaxed);
+        self.reconnected.notify_waiters();
+        client_id
+    }
+
+    /// Create channel for requests to a newly connected core, see `add_core_stream`.
+    pub(crate) fn request_channel(
+        &self,
+    ) -> (RequestSender, mpsc::Receiver<Result<CoreRequest, Status>>) {
+        mpsc::channel(self.queue_size.get())
+    }
+
+    /// Add core connected at `address`, which is sent requests through `tx` and responds over
+    /// `stream`. Returns the task reading `stream`, which ends when the core disconnects.
+    pub(crate) fn add_core_stream(
+        &self,
+        address: SocketAddr,
+        version: Version,
+        tx: RequestSender,
+        mut stream: Streaming<CoreResponse>,
+    ) -> JoinHandle<()> {
+        let connected_at = Instant::now();
+        let awaiting_since = Arc::new(Mutex::new(None));
+        let (removed_tx, mut removed_rx) = oneshot::channel();
+        let client_id = self.add_client(CoreClient {
+            address,
+            version,
+            connected_at,
+            tx,
+            awaiting_since: Arc::clone(&awaiting_since),
+            _removed: removed_tx,
+        });
+
+        let clients = Arc::clone(&self.clients);
+        let pending = Arc::clone(&self.pending);
+        let connected = Arc::clone(&self.connected);
+        tokio::spawn(
+            async move {
+                loop {
+                    let message = tokio::select! {
+                        message = stream.message() => message,
+                        _ = &mut removed_rx => {
+                            info!("Stopped reading gRPC stream of removed Defguard Core");
+                            break;
+                        }
+                    };
+                    match message {
+                        Ok(Some(response)) => {
+                            debug!("Received message from Defguard Core ID={}", response.id);
+                            *awaiting_since.lock().unwrap() = None;
+                            connected.store(true, Ordering::Relaxed);
+                            // Discard empty payloads.
+                            if let Some(payload) = response.payload {
+                                if !pending.complete(client_id, response.id, payload) {
+                                    error!(
+                                        "No pending request for response #{}, it has expired or \
+                                        was sent over another stream",
+                                        response.id
+                                    );
+                                }
+                            }
+                        }
+                        Ok(None) => {
+                            info!("gRPC stream has been closed");
+                            break;
+                        }
+                        Err(err) if err.code() == Code::OutOfRange => {
+                            error!(
+                                "Message from Defguard Core exceeds maximum gRPC message size \
+                                (grpc_max_decoding_message_size): {}",
+                                err.message()
+                            );
+                            break;
+                        }
+                        Err(err) => {
+                            error!("gRPC client error: {err}");
+                            break;
+                        }
+                    }
+                }
+                info!(
+                    "Defguard core client disconnected: {address}, connected for {}s",
+                    connected_at.elapsed().as_secs()
+                );
+                {
+                    let mut clients = clients.lock().unwrap();
+                    clients.remove(&client_id);
+                    connected.store(!clients.is_empty(), Ordering::Relaxed);
+                }
+                let failed = pending.fail_session(client_id);
+                if failed > 0 {
+                    warn!("Failed {failed} requests pending on disconnected Defguard Core");
+                }
+            }
+            .instrument(tracing::Span::current()),
+        )
+    }
+
+    /// Picks the core to route the next request to. With round-robin selection `advance`
+    /// decides whether to move on to the next core.
+    fn select(&self, clients: &ClientMap, advance: bool) -> Option<u64> {
+        match self.selection {
+            CoreSelection::Primary => clients.keys().next().copied(),
+            CoreSelection::MostRecent => clients.keys().next_back().copied(),
+            CoreSelection::RoundRobin => {
+                if clients.is_empty() {
+                    return None;
+                }
+                let index = if advance {
+                    self.round_robin.fetch_add(1, Ordering::Relaxed)
+                } else {
+                    self.round_robin.load(Ordering::Relaxed)
+                };
+                clients.keys().nth(index % clients.len()).copied()
+            }
+        }
+    }
+
+    /// Periodically evict expired pending requests.
+    pub(crate) async fn run_pending_cleanup(self) {
+        self.pending.run_cleanup().await;
+    }
+
+    /// Version of the core which will handle the next request.
+    #[must_use]
+    pub(crate) fn core_version(&self) -> Option<Version> {
+        let clients = self.clients.lock().unwrap();
+        self.select(&clients, false)
+            .map(|key| clients[&key].version.clone())
+    }
+
+    /// Drop connections to cores which haven't sent anything for `stale_timeout`, while
+    /// requests are waiting for them. Returns number of dropped connections.
+    fn drop_stale(&self, stale_timeout: Duration) -> usize {
+        let mut clients = self.clients.lock().unwrap();
+        let count = clients.len();
+        clients.retain(|_, client| {
+            let awaiting_since = *client.awaiting_since.lock().unwrap();
+            let stale = awaiting_since.is_some_and(|since| since.elapsed() >= stale_timeout);
+            if stale {
+                warn!(
+                    "Defguard Core {} hasn't responded for {}s, dropping the connection",
+                    client.address,
+                    stale_timeout.as_secs()
+                );
+            }
+            !stale
+        });
+        self.connected.store(!clients.is_empty(), Ordering::Relaxed);
+        count - clients.len()
+    }
+
+    /// Periodically drop connections to cores which stopped responding.
+    pub(crate) async fn run_stale_check(self, stale_timeout: Duration) {
+        loop {
+            tokio::time::sleep(STALE_CHECK_PERIOD).await;
+            self.drop_stale(stale_timeout);
+        }
+    }
+
+    /// Keep gRPC health status of the server and the proxy service up to date: they are
+    /// serving while a core is connected.
+    pub(crate) async fn run_health_reporter(self, reporter: HealthReporter) {
+        let mut last_connected = None;
+        loop {
+            let connected = self.connected.load(Ordering::Relaxed);
+            if last_connected != Some(connected) {
+                let status = if connected {
+                    ServingStatus::Serving
+                } else {
+                    ServingStatus::NotServing
+                };
+                debug!("Setting gRPC health status to {status:?}");
+                reporter.set_service_status("", status).await;
+                reporter
+                    .set_service_status(
+                        <proxy_server::ProxyServer<Self> as NamedService>::NAME,
+                        status,
+                    )
+                    .await;
+                last_connected = Some(connected);
+            }
+            tokio::time::sleep(HEALTH_UPDATE_PERIOD).await;
+        }
+    }
+
+    /// Number of requests queued for each connected core, in connection order.
+    #[must_use]
+    pub(crate) fn queue_depths(&self) -> Vec<usize> {
+        self.clients
+            .lock()
+            .unwrap()
+            .values()
+            .map(|client| client.tx.max_capacity() - client.tx.capacity())
+            .collect()
+    }
+
+    /// Report connected cores, and requests queued for them or awaiting their response as
+    /// OpenTelemetry metrics.
+    pub(crate) fn register_metrics(&self) {
+        let meter = global::meter("defguard-proxy");
+        let clients = Arc::clone(&self.clients);
+        meter
+            .u64_observable_gauge("defguard_proxy.core.connections")
+            .with_description("Number of connected Defguard Core streams by Core version")
+            .with_callback(move |observer| {
+                let mut versions = BTreeMap::<_, u64>::new();
+                for client in clients.lock().unwrap().values() {
+                    *versions.entry(client.version.to_string()).or_default() += 1;
+                }
+                for (version, count) in versions {
+                    observer.observe(count, &[KeyValue::new("version", version)]);
+                }
+            })
+            .build();
+        let server = self.clone();
+        meter
+            .u64_observable_gauge("defguard_proxy.core.queued_requests")
+            .with_description("Number of requests queued for Defguard Core instances")
+            .with_callback(move |observer| {
+                observer.observe(server.queue_depths().iter().sum::<usize>() as u64, &[]);
+            })
+            .build();
+        let pending = Arc::clone(&self.pending);
+        meter
+            .u64_observable_gauge("defguard_proxy.core.pending_requests")
+            .with_description("Number of requests awaiting Defguard Core response")
+            .with_callback(move |observer| observer.observe(pending.len() as u64, &[]))
+            .build();
+    }
+
+    /// End streams of all connected cores, which stops reading their messages. Requests still
+    /// awaiting a response fail.
+    pub(crate) fn close_streams(&self) {
+        let mut clients = self.clients.lock().unwrap();
+        for client in clients.values() {
+            info!("Closing gRPC stream of Defguard Core {}", client.address);
         }
+        clients.clear();
+        self.connected.store(false, Ordering::Relaxed);
+    }
+
+    /// Maximum number of requests queued for a single core.
+    #[must_use]
+    pub(crate) fn queue_size(&self) -> usize {
+        self.queue_size.get()
     }
 
     /// Sends message to the other side of RPC, with given `payload` and optional `device_info`.
     /// Returns `tokio::sync::oneshot::Reveicer` to let the caller await reply.
+    /// If the selected core's stream is gone, the message is rerouted to the next available one.
+    /// Fails with `ApiError::CoreBusy` if the selected core's request queue is full.
+    /// If no core is connected, the message is held for the reconnect grace period, if enabled.
     #[instrument(name = "send_grpc_message", level = "debug", skip(self, payload))]
-    pub(crate) fn send(
+    pub(crate) async fn send(
         &self,
         payload: core_request::Payload,
         device_info: DeviceInfo,
     ) -> Result<oneshot::Receiver<core_response::Payload>, ApiError> {
-        if let Some(client_tx) = self.clients.lock().unwrap().values().next() {
-            let id = self.current_id.fetch_add(1, Ordering::Relaxed);
-            let res = CoreRequest {
-                id,
-                device_info: Some(device_info),
-                payload: Some(payload),
-            };
-            if let Err(err) = client_tx.send(Ok(res)) {
-                error!("Failed to send CoreRequest: {err}");
-                return Err(ApiError::Unexpected("Failed to send CoreRequest".into()));
-            }
-            let (tx, rx) = oneshot::channel();
-            let mut results = self.results.lock().unwrap();
-            results.insert(id, tx);
-            self.connected.store(true, Ordering::Relaxed);
-            Ok(rx)
-        } else {
+        let id = self.current_id.fetch_add(1, Ordering::Relaxed);
+        // TODO: send request ID and `traceparent` of `RequestContext` once `CoreRequest` has
+        // fields for them in the proto definitions.
+        let request = CoreRequest {
+            id,
+            device_info: Some(device_info),
+            payload: Some(payload),
+        };
+        let size = request.encoded_len();
+        if size > self.max_message_size {
+            error!(
+                "Request #{id} is {size} bytes long, exceeding maximum gRPC message size of {} \
+                bytes (grpc_max_encoding_message_size)",
+                self.max_message_size
+            );
+            return Err(ApiError::Unexpected("Request too large".into()));
+        }
+        let mut request = match self.route(request) {
+            Routed::Sent(rx) => return Ok(rx),
+            Routed::Busy => return Err(ApiError::CoreBusy),
+            Routed::NotConnected(request) => request,
+        };
+
+        let held = (!self.reconnect_grace.is_zero())
+            .then(|| HeldRequest::acquire(&self.held, self.max_held))
+            .flatten();
+        let Some(_held) = held else {
             error!("Defguard Core is not connected");
-            self.connected.store(false, Ordering::Relaxed);
-            Err(ApiError::Unexpected(
-                "Defguard Core is not connected".into(),
-            ))
+            return Err(ApiError::CoreNotConnected);
+        };
+        info!("Defguard Core is not connected, holding request until it reconnects");
+        let routed = timeout(self.reconnect_grace, async {
+            loop {
+                let reconnected = self.reconnected.notified();
+                tokio::pin!(reconnected);
+                // register for notification before checking, so a reconnection isn't missed
+                reconnected.as_mut().enable();
+                match self.route(request) {
+                    Routed::NotConnected(returned) => request = returned,
+                    routed => return routed,
+                }
+                reconnected.await;
+            }
+        })
+        .await;
+        match routed {
+            Ok(Routed::Sent(rx)) => Ok(rx),
+            Ok(Routed::Busy) => Err(ApiError::CoreBusy),
+            Ok(Routed::NotConnected(_)) | Err(_) => {
+                error!(
+                    "Defguard Core didn't reconnect within {}s",
+                    self.reconnect_grace.as_secs()
+                );
+                Err(ApiError::CoreNotConnected)
+            }
+        }
+    }
+
+    /// Routes `request` to the selected core.
+    fn route(&self, request: CoreRequest) -> Routed {
+        let id = request.id;
+        let name = request.payload.as_ref().map_or("empty", core_request_name);
+        let mut request = Ok(request);
+        let mut clients = self.clients.lock().unwrap();
+        while let Some(key) = self.select(&clients, true) {
+            let client = &clients[&key];
+            debug!(
+                "Routing request #{id} to Defguard Core {}, version {}, connected {}s ago",
+                client.address,
+                client.version,
+                client.connected_at.elapsed().as_secs()
+            );
+            let rx = self.pending.register(key, id, name);
+            match client.tx.try_send(request) {
+                Ok(()) => {
+                    client
+                        .awaiting_since
+                        .lock()
+                        .unwrap()
+                        .get_or_insert_with(Instant::now);
+                    self.connected.store(true, Ordering::Relaxed);
+                    return Routed::Sent(rx);
+                }
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "Defguard Core {} request queue is full ({} requests), rejecting request",
+                        client.address, self.queue_size
+                    );
+                    self.pending.remove(key, id);
+                    return Routed::Busy;
+                }
+                Err(TrySendError::Closed(returned)) => {
+                    warn!(
+                        "Defguard Core {} stream is closed, rerouting request",
+                        client.address
+                    );
+                    self.pending.remove(key, id);
+                    clients.remove(&key);
+                    request = returned;
+                }
+            }
+        }
+
+        self.connected.store(false, Ordering::Relaxed);
+        match request {
+            Ok(request) => Routed::NotConnected(request),
+            Err(_) => unreachable!("only requests are routed to cores"),
         }
     }
 }
@@ -81,17 +531,25 @@ impl Clone for ProxyServer {
     fn clone(&self) -> Self {
         Self {
             current_id: Arc::clone(&self.current_id),
+            next_client_id: Arc::clone(&self.next_client_id),
             clients: Arc::clone(&self.clients),
-            results: Arc::clone(&self.results),
+            pending: Arc::clone(&self.pending),
             connected: Arc::clone(&self.connected),
-            core_version: Arc::clone(&self.core_version),
+            selection: self.selection,
+            round_robin: Arc::clone(&self.round_robin),
+            queue_size: self.queue_size,
+            reconnected: Arc::clone(&self.reconnected),
+            reconnect_grace: self.reconnect_grace,
+            max_held: self.max_held,
+            held: Arc::clone(&self.held),
+            max_message_size: self.max_message_size,
         }
     }
 }
 
 #[tonic::async_trait]
 impl proxy_server::Proxy for ProxyServer {
-    type BidiStream = UnboundedReceiverStream<Result<CoreRequest, Status>>;
+    type BidiStream = ReceiverStream<Result<CoreRequest, Status>>;
 
     /// Handle bidirectional communication with Defguard core.
     #[instrument(name = "bidirectional_communication", level = "info", skip(self))]
@@ -105,58 +563,157 @@ impl proxy_server::Proxy for ProxyServer {
         };
         let maybe_info = ComponentInfo::from_metadata(request.metadata());
         let (version, info) = get_tracing_variables(&maybe_info);
-        let mut core_version = self.core_version.lock().unwrap();
-        *core_version = Some(version.clone());
 
         let span = tracing::info_span!("core_bidi_stream", component = %DefguardComponent::Core,
             version = version.to_string(), info);
         let _guard = span.enter();
 
         info!("Defguard Core gRPC client connected from: {address}");
+        if let Some(certs) = request.peer_certs() {
+            match certs.first().map(cert_names) {
+                Some(Ok(names)) => info!(
+                    "Defguard Core client certificate verified, identity: {}",
+                    names.join(", ")
+                ),
+                Some(Err(err)) => warn!("Failed to read Defguard Core client certificate: {err}"),
+                None => (),
+            }
+        }
 
-        let (tx, rx) = mpsc::unbounded_channel();
-        self.clients.lock().unwrap().insert(address, tx);
-        self.connected.store(true, Ordering::Relaxed);
+        let (tx, rx) = self.request_channel();
+        self.add_core_stream(address, version, tx, request.into_inner());
 
-        let clients = Arc::clone(&self.clients);
-        let results = Arc::clone(&self.results);
-        let connected = Arc::clone(&self.connected);
-        let mut stream = request.into_inner();
-        tokio::spawn(
-            async move {
-                loop {
-                    match stream.message().await {
-                        Ok(Some(response)) => {
-                            debug!("Received message from Defguard Core ID={}", response.id);
-                            connected.store(true, Ordering::Relaxed);
-                            // Discard empty payloads.
-                            if let Some(payload) = response.payload {
-                                if let Some(rx) = results.lock().unwrap().remove(&response.id) {
-                                    if let Err(err) = rx.send(payload) {
-                                        error!("Failed to send message to rx {:?}", err.type_id());
-                                    }
-                                } else {
-                                    error!("Missing receiver for response #{}", response.id);
-                                }
-                            }
-                        }
-                        Ok(None) => {
-                            info!("gRPC stream has been closed");
-                            break;
-                        }
-                        Err(err) => {
-                            error!("gRPC client error: {err}");
-                            break;
-                        }
-                    }
-                }
-                info!("Defguard core client disconnected: {address}");
-                connected.store(false, Ordering::Relaxed);
-                clients.lock().unwrap().remove(&address);
-            }
-            .instrument(tracing::Span::current()),
-        );
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::InstanceInfoRequest;
+
+    fn new_server(selection: CoreSelection) -> ProxyServer {
+        ProxyServer::new(selection, NonZeroUsize::new(2).unwrap())
+    }
+
+    fn add_client(server: &ProxyServer, port: u16) -> mpsc::Receiver<Result<CoreRequest, Status>> {
+        let (tx, rx) = mpsc::channel(server.queue_size());
+        server.add_client(CoreClient {
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+            version: Version::new(1, 6, u64::from(port)),
+            connected_at: Instant::now(),
+            tx,
+            awaiting_since: Arc::new(Mutex::new(None)),
+            _removed: oneshot::channel().0,
+        });
+        rx
+    }
+
+    async fn send(
+        server: &ProxyServer,
+    ) -> Result<oneshot::Receiver<core_response::Payload>, ApiError> {
+        server
+            .send(
+                core_request::Payload::InstanceInfo(InstanceInfoRequest::default()),
+                DeviceInfo::default(),
+            )
+            .await
+    }
+
+    fn core_version(server: &ProxyServer) -> Option<String> {
+        server.core_version().map(|version| version.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_core_selection() {
+        let server = new_server(CoreSelection::Primary);
+        let mut first = add_client(&server, 1);
+        let _second = add_client(&server, 2);
+        assert_eq!(core_version(&server), Some("1.6.1".into()));
+        send(&server).await.unwrap();
+        assert!(first.try_recv().is_ok());
+
+        let server = new_server(CoreSelection::MostRecent);
+        let _first = add_client(&server, 1);
+        let _second = add_client(&server, 2);
+        assert_eq!(core_version(&server), Some("1.6.2".into()));
+
+        let server = new_server(CoreSelection::RoundRobin);
+        let mut first = add_client(&server, 1);
+        let mut second = add_client(&server, 2);
+        send(&server).await.unwrap();
+        send(&server).await.unwrap();
+        assert!(first.try_recv().is_ok());
+        assert!(second.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_failover_to_next_core() {
+        let server = new_server(CoreSelection::Primary);
+        drop(add_client(&server, 1));
+        let mut second = add_client(&server, 2);
+        send(&server).await.unwrap();
+        assert!(second.try_recv().is_ok());
+        assert_eq!(core_version(&server), Some("1.6.2".into()));
+
+        drop(second);
+        assert!(send(&server).await.is_err());
+        assert!(!server.connected.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_rejects_requests() {
+        let server = new_server(CoreSelection::Primary);
+        let mut first = add_client(&server, 1);
+        send(&server).await.unwrap();
+        send(&server).await.unwrap();
+        assert_eq!(server.queue_depths(), vec![2]);
+        assert!(matches!(send(&server).await, Err(ApiError::CoreBusy)));
+
+        first.try_recv().unwrap().unwrap();
+        assert_eq!(server.queue_depths(), vec![1]);
+        send(&server).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drop_stale_cores() {
+        let server = new_server(CoreSelection::Primary);
+        let _first = add_client(&server, 1);
+        assert_eq!(server.drop_stale(Duration::ZERO), 0);
+
+        // request awaits response
+        send(&server).await.unwrap();
+        assert_eq!(server.drop_stale(Duration::from_secs(60)), 0);
+        assert_eq!(server.drop_stale(Duration::ZERO), 1);
+        assert!(!server.connected.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_requests_held_until_reconnect() {
+        let server =
+            new_server(CoreSelection::Primary).with_reconnect_grace(Duration::from_secs(5), 1);
+        let held = tokio::spawn({
+            let server = server.clone();
+            async move { send(&server).await }
+        });
+        while server.held.load(Ordering::Relaxed) == 0 {
+            tokio::task::yield_now().await;
+        }
+        // limit of held requests reached
+        assert!(matches!(
+            send(&server).await,
+            Err(ApiError::CoreNotConnected)
+        ));
+        let mut core = add_client(&server, 1);
+        held.await.unwrap().unwrap();
+        assert!(core.try_recv().is_ok());
 
-        Ok(Response::new(UnboundedReceiverStream::new(rx)))
+        // grace period expires
+        let server =
+            new_server(CoreSelection::Primary).with_reconnect_grace(Duration::from_millis(10), 1);
+        assert!(matches!(
+            send(&server).await,
+            Err(ApiError::CoreNotConnected)
+        ));
     }
 }
@@ -1,6 +1,6 @@
 use axum::{
     extract::{
-        ws::{Message, WebSocket},
+        ws::{close_code, CloseFrame, Message, WebSocket},
         Query, State, WebSocketUpgrade,
     },
     response::{IntoResponse, Response},
@@ -17,6 +17,7 @@ use crate::{
     error::ApiError,
     handlers::get_core_response,
     http::AppState,
+    metrics::metrics,
     proto::{
         core_request, core_response, ClientMfaFinishRequest, ClientMfaFinishResponse,
         ClientMfaStartRequest, ClientMfaStartResponse, DeviceInfo,
@@ -46,14 +47,17 @@ async fn await_remote_auth(
 ) -> Result<Response, impl IntoResponse> {
     let token = req.token;
     // let core validate token first
-    let rx = state.grpc_server.send(
-        core_request::Payload::ClientMfaTokenValidation(
-            crate::proto::ClientMfaTokenValidationRequest {
-                token: token.clone(),
-            },
-        ),
-        device_info,
-    )?;
+    let rx = state
+        .grpc_server
+        .send(
+            core_request::Payload::ClientMfaTokenValidation(
+                crate::proto::ClientMfaTokenValidationRequest {
+                    token: token.clone(),
+                },
+            ),
+            device_info,
+        )
+        .await?;
     let payload = get_core_response(rx).await?;
     if let core_response::Payload::ClientMfaTokenValidation(response) = payload {
         if !response.token_valid {
@@ -75,6 +79,8 @@ async fn await_remote_auth(
 
 /// Handle axum web socket upgrade for `await_remote_auth`.
 async fn handle_remote_auth_socket(socket: WebSocket, state: AppState, token: String) {
+    // shutdown waits for the session to end
+    let _tracked = state.shutdown.track();
     let (tx, rx) = oneshot::channel();
 
     {
@@ -88,12 +94,26 @@ async fn handle_remote_auth_socket(socket: WebSocket, state: AppState, token: St
             }
         }
     }
+    metrics().remote_mfa_sessions.add(1, &[]);
 
     let (mut ws_tx, mut ws_rx) = socket.split();
     let mut set = JoinSet::new();
 
+    let shutdown = state.shutdown.clone();
     set.spawn(async move {
-        if let Ok(msg) = rx.await {
+        let received = tokio::select! {
+            received = rx => received,
+            () = shutdown.started() => {
+                debug!("Closing remote desktop mfa WS session on shutdown");
+                let frame = CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Server is shutting down".into(),
+                };
+                let _ = ws_tx.send(Message::Close(Some(frame))).await;
+                return;
+            }
+        };
+        if let Ok(msg) = received {
             let payload = json!({
                 "type": "mfa_success",
                 "preshared_key": &msg,
@@ -132,6 +152,7 @@ async fn handle_remote_auth_socket(socket: WebSocket, state: AppState, token: St
     set.shutdown().await;
     // This will remove token, if it's still there.
     state.remote_mfa_sessions.lock().await.remove(&token);
+    metrics().remote_mfa_sessions.add(-1, &[]);
 }
 
 #[instrument(level = "debug", skip(state, req))]
@@ -141,10 +162,13 @@ async fn start_client_mfa(
     Json(req): Json<ClientMfaStartRequest>,
 ) -> Result<Json<ClientMfaStartResponse>, ApiError> {
     info!("Starting desktop client authorization {req:?}");
-    let rx = state.grpc_server.send(
-        core_request::Payload::ClientMfaStart(req.clone()),
-        device_info,
-    )?;
+    let rx = state
+        .grpc_server
+        .send(
+            core_request::Payload::ClientMfaStart(req.clone()),
+            device_info,
+        )
+        .await?;
     let payload = get_core_response(rx).await?;
 
     if let core_response::Payload::ClientMfaStart(response) = payload {
@@ -165,7 +189,8 @@ async fn finish_client_mfa(
     info!("Finishing desktop client authorization");
     let rx = state
         .grpc_server
-        .send(core_request::Payload::ClientMfaFinish(req), device_info)?;
+        .send(core_request::Payload::ClientMfaFinish(req), device_info)
+        .await?;
     let payload = get_core_response(rx).await?;
     if let core_response::Payload::ClientMfaFinish(response) = payload {
         Ok(Json(response))
@@ -184,7 +209,8 @@ async fn finish_remote_mfa(
     info!("Finishing desktop client authorization");
     let rx = state
         .grpc_server
-        .send(core_request::Payload::ClientMfaFinish(req), device_info)?;
+        .send(core_request::Payload::ClientMfaFinish(req), device_info)
+        .await?;
     let payload = get_core_response(rx).await?;
     if let core_response::Payload::ClientMfaFinish(response) = payload {
         // Check if this needs to be forwarded.
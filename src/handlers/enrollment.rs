@@ -45,7 +45,8 @@ async fn start_enrollment_process(
     debug!("Sending the enrollment process request to core service.");
     let rx = state
         .grpc_server
-        .send(core_request::Payload::EnrollmentStart(req), device_info)?;
+        .send(core_request::Payload::EnrollmentStart(req), device_info)
+        .await?;
     let payload = get_core_response(rx).await?;
     debug!("Receving payload from the core service. Try to set private cookie for starting enrollment process.");
     if let core_response::Payload::EnrollmentStart(response) = payload {
@@ -83,7 +84,8 @@ async fn activate_user(
     debug!("Sending the activate user request to core service.");
     let rx = state
         .grpc_server
-        .send(core_request::Payload::ActivateUser(req), device_info)?;
+        .send(core_request::Payload::ActivateUser(req), device_info)
+        .await?;
     let payload = get_core_response(rx).await?;
     debug!("Receiving payload from the core service. Trying to remove private cookie...");
     if let core_response::Payload::Empty(()) = payload {
@@ -116,7 +118,8 @@ async fn create_device(
 
     let rx = state
         .grpc_server
-        .send(core_request::Payload::NewDevice(req), device_info)?;
+        .send(core_request::Payload::NewDevice(req), device_info)
+        .await?;
     let payload = get_core_response(rx).await?;
     if let core_response::Payload::DeviceConfig(response) = payload {
         info!("Added new device {name} {pubkey}");
@@ -144,7 +147,8 @@ async fn get_network_info(
 
     let rx = state
         .grpc_server
-        .send(core_request::Payload::ExistingDevice(req), device_info)?;
+        .send(core_request::Payload::ExistingDevice(req), device_info)
+        .await?;
     let payload = get_core_response(rx).await?;
     if let core_response::Payload::DeviceConfig(response) = payload {
         info!("Got network info for device {pubkey}");
@@ -49,10 +49,13 @@ pub(crate) async fn register_mobile_auth(
         auth_pub_key: req.auth_pub_key,
         device_pub_key: req.device_pub_key,
     };
-    let rx = state.grpc_server.send(
-        core_request::Payload::RegisterMobileAuth(send_data),
-        device_info,
-    )?;
+    let rx = state
+        .grpc_server
+        .send(
+            core_request::Payload::RegisterMobileAuth(send_data),
+            device_info,
+        )
+        .await?;
     let payload = get_core_response(rx).await?;
     if let core_response::Payload::Empty(()) = payload {
         info!("Registered mobile device for auth");
@@ -1,13 +1,14 @@
 use std::time::Duration;
 
 use axum::{extract::FromRequestParts, http::request::Parts};
-use axum_client_ip::{InsecureClientIp, LeftmostXForwardedFor};
 use axum_extra::{headers::UserAgent, TypedHeader};
 use tokio::{sync::oneshot::Receiver, time::timeout};
 use tonic::Code;
 
 use super::proto::DeviceInfo;
-use crate::{error::ApiError, proto::core_response::Payload};
+use crate::{
+    client_ip::ClientIp, error::ApiError, metrics::metrics, proto::core_response::Payload,
+};
 
 pub(crate) mod desktop_client_mfa;
 pub(crate) mod enrollment;
@@ -17,7 +18,7 @@ pub(crate) mod polling;
 pub(crate) mod register_mfa;
 
 // Timeout for awaiting response from Defguard Core.
-const CORE_RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+pub(crate) const CORE_RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
 const CLIENT_VERSION_HEADER: &str = "defguard-client-version";
 const CLIENT_PLATFORM_HEADER: &str = "defguard-client-platform";
 
@@ -28,12 +29,6 @@ where
     type Rejection = ApiError;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let forwarded_for_ip = LeftmostXForwardedFor::from_request_parts(parts, state)
-            .await
-            .map(|v| v.0);
-        let insecure_ip = InsecureClientIp::from_request_parts(parts, state)
-            .await
-            .map(|v| v.0);
         let user_agent = TypedHeader::<UserAgent>::from_request_parts(parts, state)
             .await
             .map(|v| v.to_string())
@@ -52,10 +47,11 @@ where
             .and_then(|v| v.to_str().ok())
             .map(str::to_string);
 
-        let ip_address = forwarded_for_ip
-            .or(insecure_ip)
-            .map(|v| v.to_string())
-            .map_err(|_| ApiError::Unexpected("Missing client IP".to_string()))?;
+        let ip_address = parts
+            .extensions
+            .get::<ClientIp>()
+            .map(|ip| ip.0.to_string())
+            .ok_or_else(|| ApiError::Unexpected("Missing client IP".to_string()))?;
 
         Ok(Self {
             ip_address,
@@ -89,10 +85,11 @@ pub(crate) async fn get_core_response(rx: Receiver<Payload>) -> Result<Payload,
             );
             return Err(core_error.into());
         }
-        core_response
-            .map_err(|err| ApiError::Unexpected(format!("Failed to receive core response: {err}")))
+        // sender is dropped when Core disconnects before responding
+        core_response.map_err(|_| ApiError::CoreDisconnected)
     } else {
         error!("Did not receive response from Core within {CORE_RESPONSE_TIMEOUT:?}");
+        metrics().core_timeouts.add(1, &[]);
         Err(ApiError::CoreTimeout)
     }
 }
@@ -154,7 +151,7 @@ mod tests {
         for agent in VALID_USER_AGENTS {
             let req = Request::builder()
                 .header("User-Agent", *agent)
-                .header("X-Forwarded-For", "10.0.0.1")
+                .extension(ClientIp([10, 0, 0, 1].into()))
                 .body(Body::empty())
                 .unwrap();
             let (parts, _) = req.into_parts();
@@ -171,7 +168,7 @@ mod tests {
         for agent in INVALID_USER_AGENTS {
             let req = Request::builder()
                 .header("User-Agent", *agent)
-                .header("X-Forwarded-For", "10.0.0.1")
+                .extension(ClientIp([10, 0, 0, 1].into()))
                 .body(Body::empty())
                 .unwrap();
             let (parts, _) = req.into_parts();
@@ -186,7 +183,7 @@ mod tests {
 
         // no user agent
         let req = Request::builder()
-            .header("X-Forwarded-For", "10.0.0.1")
+            .extension(ClientIp([10, 0, 0, 1].into()))
             .body(Body::empty())
             .unwrap();
         let (parts, _) = req.into_parts();
@@ -197,5 +194,6 @@ mod tests {
             .expect("should succeed");
 
         assert!(device_info.user_agent.is_none());
+        assert_eq!(device_info.ip_address, "10.0.0.1");
     }
 }
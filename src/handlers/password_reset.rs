@@ -27,10 +27,13 @@ async fn request_password_reset(
 ) -> Result<(), ApiError> {
     info!("Starting password reset request for {}", req.email);
 
-    let rx = state.grpc_server.send(
-        core_request::Payload::PasswordResetInit(req.clone()),
-        device_info,
-    )?;
+    let rx = state
+        .grpc_server
+        .send(
+            core_request::Payload::PasswordResetInit(req.clone()),
+            device_info,
+        )
+        .await?;
     let payload = get_core_response(rx).await?;
     if let core_response::Payload::Empty(()) = payload {
         info!("Started password reset request for {}", req.email);
@@ -60,7 +63,8 @@ async fn start_password_reset(
 
     let rx = state
         .grpc_server
-        .send(core_request::Payload::PasswordResetStart(req), device_info)?;
+        .send(core_request::Payload::PasswordResetStart(req), device_info)
+        .await?;
     let payload = get_core_response(rx).await?;
     if let core_response::Payload::PasswordResetStart(response) = payload {
         // set session cookie
@@ -91,7 +95,8 @@ async fn reset_password(
 
     let rx = state
         .grpc_server
-        .send(core_request::Payload::PasswordReset(req), device_info)?;
+        .send(core_request::Payload::PasswordReset(req), device_info)
+        .await?;
     let payload = get_core_response(rx).await?;
     if let core_response::Payload::Empty(()) = payload {
         if let Some(cookie) = private_cookies.get(PASSWORD_RESET_COOKIE_NAME) {
@@ -14,10 +14,13 @@ pub(crate) async fn info(
     Json(req): Json<InstanceInfoRequest>,
 ) -> Result<Json<InstanceInfoResponse>, ApiError> {
     debug!("Retrieving info for polling request");
-    let rx = state.grpc_server.send(
-        core_request::Payload::InstanceInfo(req.clone()),
-        device_info,
-    )?;
+    let rx = state
+        .grpc_server
+        .send(
+            core_request::Payload::InstanceInfo(req.clone()),
+            device_info,
+        )
+        .await?;
     let payload = get_core_response(rx).await?;
 
     if let core_response::Payload::InstanceInfo(response) = payload {
@@ -43,13 +43,16 @@ async fn register_code_mfa_start(
         return Err(ApiError::BadRequest("Method not supported.".to_string()));
     }
 
-    let rx = state.grpc_server.send(
-        core_request::Payload::CodeMfaSetupStart(CodeMfaSetupStartRequest {
-            token,
-            method: req.method.into(),
-        }),
-        device_info,
-    )?;
+    let rx = state
+        .grpc_server
+        .send(
+            core_request::Payload::CodeMfaSetupStart(CodeMfaSetupStartRequest {
+                token,
+                method: req.method.into(),
+            }),
+            device_info,
+        )
+        .await?;
     let payload = get_core_response(rx).await?;
     match payload {
         core_response::Payload::CodeMfaSetupStartResponse(response) => Ok(Json(response)),
@@ -83,14 +86,17 @@ async fn register_code_mfa_finish(
         return Err(ApiError::BadRequest("Method not supported".to_string()));
     }
 
-    let rx = state.grpc_server.send(
-        core_request::Payload::CodeMfaSetupFinish(CodeMfaSetupFinishRequest {
-            token,
-            code,
-            method: method as i32,
-        }),
-        device_info,
-    )?;
+    let rx = state
+        .grpc_server
+        .send(
+            core_request::Payload::CodeMfaSetupFinish(CodeMfaSetupFinishRequest {
+                token,
+                code,
+                method: method as i32,
+            }),
+            device_info,
+        )
+        .await?;
     let payload = get_core_response(rx).await?;
     match payload {
         core_response::Payload::CodeMfaSetupFinishResponse(response) => Ok(Json(response)),
@@ -1,19 +1,27 @@
 use std::{
     collections::HashMap,
-    fs::read_to_string,
+    future::Future,
+    io,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::{atomic::Ordering, Arc},
+    sync::{atomic::Ordering, Arc, RwLock},
     time::Duration,
 };
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use axum::{
     body::Body,
     extract::{ConnectInfo, FromRef, State},
-    http::{header::HeaderValue, Request, Response, StatusCode},
+    http::{
+        header::{HeaderValue, CONTENT_TYPE, HOST},
+        uri::{Authority, PathAndQuery},
+        HeaderMap, Request, Response, StatusCode, Uri,
+    },
     middleware::{self, Next},
+    response::Redirect,
     routing::{get, post},
-    serve, Json, Router,
+    serve,
+    serve::{Listener, ListenerExt},
+    Json, Router,
 };
 use axum_extra::extract::cookie::Key;
 use clap::crate_version;
@@ -21,25 +29,61 @@ use defguard_version::{
     server::{grpc::DefguardVersionInterceptor, DefguardVersionLayer},
     DefguardComponent, Version,
 };
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::{conn::auto, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry_http::HeaderExtractor;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
 use serde::Serialize;
-use tokio::{net::TcpListener, sync::oneshot, task::JoinSet};
-use tonic::transport::{Identity, Server, ServerTlsConfig};
-use tower::ServiceBuilder;
-use tower_governor::{
-    governor::GovernorConfigBuilder, key_extractor::SmartIpKeyExtractor, GovernorLayer,
+use time::OffsetDateTime;
+use tokio::{
+    net::TcpListener,
+    sync::oneshot,
+    task::{JoinError, JoinSet},
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tonic::{
+    service::Routes,
+    transport::{server::TcpConnectInfo, Server},
 };
+use tonic_health::server::health_reporter;
+use tonic_reflection::server::v1::{ServerReflection, ServerReflectionServer};
+use tower::{service_fn, ServiceBuilder, ServiceExt};
 use tower_http::trace::{self, TraceLayer};
-use tracing::{info_span, Level};
+use tracing::{field, info_span, Level};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use url::Url;
 
 use crate::{
+    admission::AdmissionInterceptor,
     assets::{index, web_asset},
-    config::Config,
+    cli::config_errors,
+    client_ip::{client_ip_middleware, ClientIp, ClientIpResolver},
+    config::{Config, TlsVersion},
+    cookies::{reencrypt_cookies_middleware, CookieKeys},
     enterprise::handlers::openid_login::{self, FlowType},
     error::ApiError,
     grpc::ProxyServer,
     handlers::{desktop_client_mfa, enrollment, password_reset, polling},
-    proto::proxy_server,
+    lockout::{lockout_middleware, Lockout},
+    logging::LogFilterHandle,
+    metrics::{http_metrics_middleware, prometheus_metrics, PrometheusReader},
+    outbound::CoreConnector,
+    proto::{proxy_server, FILE_DESCRIPTOR_SET},
+    proxy_protocol::{proxied_incoming, ProxiedListener, TrustedPeers},
+    ratelimit::{rate_limit_middleware, RateLimiter},
+    reload::{run_reload_handler, ReloadHandles},
+    request_id::request_context_middleware,
+    shutdown::{shutdown_signal, track_requests_middleware, Shutdown},
+    tls::{
+        client_config, client_verifier, format_date, server_config, tls_incoming, watch_cert_files,
+        CertResolver, TlsListener,
+    },
     MIN_CORE_VERSION, VERSION,
 };
 
@@ -47,8 +91,6 @@ pub(crate) static ENROLLMENT_COOKIE_NAME: &str = "defguard_proxy";
 pub(crate) static PASSWORD_RESET_COOKIE_NAME: &str = "defguard_proxy_password_reset";
 const DEFGUARD_CORE_CONNECTED_HEADER: &str = "defguard-core-connected";
 const DEFGUARD_CORE_VERSION_HEADER: &str = "defguard-core-version";
-const RATE_LIMITER_CLEANUP_PERIOD: Duration = Duration::from_secs(60);
-const X_FORWARDED_FOR: &str = "x-forwarded-for";
 const X_POWERED_BY: &str = "x-powered-by";
 
 #[derive(Clone)]
@@ -56,15 +98,18 @@ pub(crate) struct AppState {
     pub(crate) grpc_server: ProxyServer,
     pub(crate) remote_mfa_sessions:
         Arc<tokio::sync::Mutex<HashMap<String, oneshot::Sender<String>>>>,
-    key: Key,
-    url: Url,
+    cookie_keys: CookieKeys,
+    url: Arc<RwLock<Url>>,
+    cert_resolvers: Vec<Arc<CertResolver>>,
+    pub(crate) shutdown: Shutdown,
+    lockout: Lockout,
 }
 
 impl AppState {
     /// Returns configured URL with "auth/callback" appended to the path.
     #[must_use]
     pub(crate) fn callback_url(&self, flow_type: &FlowType) -> Url {
-        let mut url = self.url.clone();
+        let mut url = self.url.read().unwrap().clone();
         // Append "/openid/callback" to the URL.
         if let Ok(mut path_segments) = url.path_segments_mut() {
             match flow_type {
@@ -78,7 +123,13 @@ impl AppState {
 
 impl FromRef<AppState> for Key {
     fn from_ref(state: &AppState) -> Self {
-        state.key.clone()
+        Key::from_ref(&state.cookie_keys)
+    }
+}
+
+impl FromRef<AppState> for Lockout {
+    fn from_ref(state: &AppState) -> Self {
+        state.lockout.clone()
     }
 }
 
@@ -111,23 +162,189 @@ async fn healthcheckgrpc(State(state): State<AppState>) -> (StatusCode, &'static
     }
 }
 
-// Retrieves client address from the request. Uses either the left most x-forwarded-for
-// header value, or socket address if the header is not present.
+#[derive(Serialize)]
+struct QueueHealth {
+    depth: usize,
+    capacity: usize,
+}
+
+// Reports request queue depth of each connected Defguard Core. Fails if any queue is full.
+async fn healthcheckgrpcqueues(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<Vec<QueueHealth>>) {
+    let capacity = state.grpc_server.queue_size();
+    let queues: Vec<_> = state
+        .grpc_server
+        .queue_depths()
+        .into_iter()
+        .map(|depth| QueueHealth { depth, capacity })
+        .collect();
+    let status = if queues.iter().any(|queue| queue.depth >= capacity) {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (status, Json(queues))
+}
+
+#[derive(Serialize)]
+struct CertificateHealth {
+    name: &'static str,
+    not_after: String,
+    expired: bool,
+}
+
+// Reports expiry dates of served TLS certificates. Fails if any of them has expired.
+async fn healthchecktls(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<Vec<CertificateHealth>>) {
+    let now = OffsetDateTime::now_utc();
+    let certificates: Vec<_> = state
+        .cert_resolvers
+        .iter()
+        .map(|resolver| {
+            let not_after = resolver.not_after();
+            CertificateHealth {
+                name: resolver.name(),
+                not_after: format_date(not_after),
+                expired: not_after <= now,
+            }
+        })
+        .collect();
+    let status = if certificates.iter().any(|cert| cert.expired) {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (status, Json(certificates))
+}
+
+// Retrieves client address resolved by `client_ip_middleware` from the request.
 fn get_client_addr(request: &Request<Body>) -> String {
     request
-        .headers()
-        .get(X_FORWARDED_FOR)
-        .and_then(|h| h.to_str().ok())
-        .and_then(|h| h.split(',').next())
-        .map_or_else(
-            || {
-                request
-                    .extensions()
-                    .get::<ConnectInfo<SocketAddr>>()
-                    .map_or("unknown".to_string(), |addr| addr.0.to_string())
-            },
-            |ip| ip.trim().to_string(),
+        .extensions()
+        .get::<ClientIp>()
+        .map_or("unknown".to_string(), |ip| ip.0.to_string())
+}
+
+/// Routes gRPC requests to `grpc` and all other requests to `app`, so both can be served on
+/// a single port. gRPC requests get the client address in the form tonic expects.
+fn multiplex(app: Router, grpc: Router) -> Router {
+    Router::new().fallback_service(service_fn(move |mut request: Request<Body>| {
+        let is_grpc = request
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(|value| value.as_bytes().starts_with(b"application/grpc"));
+        if is_grpc {
+            if let Some(ConnectInfo(addr)) = request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .copied()
+            {
+                request.extensions_mut().insert(TcpConnectInfo {
+                    local_addr: None,
+                    remote_addr: Some(addr),
+                });
+            }
+            grpc.clone().oneshot(request)
+        } else {
+            app.clone().oneshot(request)
+        }
+    }))
+}
+
+/// Serves `app` like `axum::serve` until `stopped` completes, sending HTTP/2 keepalive pings
+/// every `interval` and closing connections which don't acknowledge them within `timeout`.
+async fn serve_with_keepalive<L>(
+    mut listener: L,
+    app: Router,
+    interval: Option<Duration>,
+    timeout: Duration,
+    stopped: impl Future<Output = ()>,
+) -> io::Result<()>
+where
+    L: Listener<Addr = SocketAddr>,
+{
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .keep_alive_interval(interval)
+        .keep_alive_timeout(timeout);
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(stopped);
+    loop {
+        let (io, addr) = tokio::select! {
+            connection = listener.accept() => connection,
+            () = &mut stopped => break,
+        };
+        let app = app.clone();
+        let service = TowerToHyperService::new(service_fn(move |mut request: Request<_>| {
+            request.extensions_mut().insert(ConnectInfo(addr));
+            app.clone().oneshot(request)
+        }));
+        let connection = builder
+            .serve_connection_with_upgrades(TokioIo::new(io), service)
+            .into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                debug!("Failed to serve connection from {addr}: {err}");
+            }
+        });
+    }
+    // stop accepting connections and wait for the open ones to finish
+    drop(listener);
+    graceful.shutdown().await;
+    Ok(())
+}
+
+// Serves the API until `stopped` completes. In single port mode gRPC keepalive settings apply
+// to all connections, like to the ones on the gRPC port.
+async fn serve_api<L>(
+    listener: L,
+    app: Router,
+    keepalive: Option<(Option<Duration>, Duration)>,
+    stopped: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()>
+where
+    L: Listener<Addr = SocketAddr>,
+{
+    if let Some((interval, timeout)) = keepalive {
+        serve_with_keepalive(listener, app, interval, timeout, stopped).await
+    } else {
+        // `TapIo` listener wrapper provides `ConnectInfo<SocketAddr>` for custom listeners
+        serve(
+            listener.tap_io(|_| ()),
+            app.into_make_service_with_connect_info::<SocketAddr>(),
         )
+        .with_graceful_shutdown(stopped)
+        .await
+    }
+}
+
+// gRPC server reflection describing the proxy and health services.
+fn reflection_service() -> anyhow::Result<ServerReflectionServer<impl ServerReflection>> {
+    tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build_v1()
+        .context("Failed to build gRPC reflection service")
+}
+
+// Builds HTTPS URL for the request, keeping the requested host and path.
+fn https_redirect_url(headers: &HeaderMap, uri: &Uri, default_host: &str, port: u16) -> String {
+    let host = headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+        .map_or_else(|| default_host.to_string(), |host| host.host().to_string());
+    let path = uri.path_and_query().map_or("/", PathAndQuery::as_str);
+    if port == 443 {
+        format!("https://{host}{path}")
+    } else {
+        format!("https://{host}:{port}{path}")
+    }
 }
 
 async fn core_version_middleware(
@@ -137,7 +354,7 @@ async fn core_version_middleware(
 ) -> Response<Body> {
     let mut response = next.run(request).await;
 
-    if let Some(core_version) = app_state.grpc_server.core_version.lock().unwrap().as_ref() {
+    if let Some(core_version) = app_state.grpc_server.core_version() {
         if let Ok(core_version_header) = HeaderValue::from_str(&core_version.to_string()) {
             response
                 .headers_mut()
@@ -166,109 +383,264 @@ async fn powered_by_header<B>(mut response: Response<B>) -> Response<B> {
     response
 }
 
-pub async fn run_server(config: Config) -> anyhow::Result<()> {
+// Loads TLS certificate if configured, and starts watching its files for changes.
+fn cert_resolver(
+    name: &'static str,
+    cert: &Option<String>,
+    key: &Option<String>,
+) -> anyhow::Result<Option<Arc<CertResolver>>> {
+    let (Some(cert), Some(key)) = (cert, key) else {
+        return Ok(None);
+    };
+    let resolver = Arc::new(
+        CertResolver::from_files(name, cert, key)
+            .with_context(|| format!("Failed to load {name} TLS certificate"))?,
+    );
+    tokio::spawn(watch_cert_files(Arc::clone(&resolver)));
+    Ok(Some(resolver))
+}
+
+pub async fn run_server(
+    config: Config,
+    log_filter: LogFilterHandle,
+    metrics: Option<PrometheusReader>,
+) -> anyhow::Result<()> {
     info!("Starting Defguard Proxy server");
-    debug!("Using config: {config:?}");
+    let errors = config_errors(&config);
+    if !errors.is_empty() {
+        bail!("Invalid configuration: {}", errors.join(", "));
+    }
 
+    // gRPC server and other long running tasks
     let mut tasks = JoinSet::new();
+    // HTTP servers, stopped first on shutdown
+    let mut http_tasks = JoinSet::new();
+    let shutdown = Shutdown::default();
+    // stops gRPC server once API requests are done
+    let core_shutdown = CancellationToken::new();
 
     // connect to upstream gRPC server
-    let grpc_server = ProxyServer::new();
+    let grpc_server = ProxyServer::new(config.core_selection, config.core_queue_size)
+        .with_reconnect_grace(
+            Duration::from_secs(config.core_reconnect_grace),
+            config.core_reconnect_max_requests,
+        )
+        .with_max_message_size(config.grpc_max_encoding_message_size);
+    grpc_server.register_metrics();
+
+    // Read TLS certificates and keys.
+    debug!("Configuring certificates for gRPC");
+    let grpc_cert_resolver = cert_resolver("gRPC", &config.grpc_cert, &config.grpc_key)?;
+    debug!("Configured gRPC certificate: {:?}", config.grpc_cert);
+    let http_cert_resolver = cert_resolver("HTTPS", &config.http_cert, &config.http_key)?;
 
     // build application
     debug!("Setting up API server");
+    let url = Arc::new(RwLock::new(config.url.clone()));
+    let lockout = Lockout::new(
+        config.lockout_threshold,
+        config.lockout_window,
+        config.lockout_duration,
+    );
     let shared_state = AppState {
         grpc_server: grpc_server.clone(),
         remote_mfa_sessions: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
-        // Generate secret key for encrypting cookies.
-        key: Key::generate(),
-        url: config.url.clone(),
+        cookie_keys: CookieKeys::from_config(&config).context("Failed to load cookie key")?,
+        url: Arc::clone(&url),
+        cert_resolvers: grpc_cert_resolver
+            .iter()
+            .chain(&http_cert_resolver)
+            .cloned()
+            .collect(),
+        shutdown: shutdown.clone(),
+        lockout: lockout.clone(),
     };
 
-    // Read gRPC TLS certificate and key.
-    debug!("Configuring certificates for gRPC");
-    let grpc_cert = config
-        .grpc_cert
-        .as_ref()
-        .and_then(|path| read_to_string(path).ok());
-    let grpc_key = config
-        .grpc_key
-        .as_ref()
-        .and_then(|path| read_to_string(path).ok());
-    debug!("Configured gRPC certificate: {grpc_cert:?}");
+    // Verify Defguard Core client certificates.
+    let grpc_client_verifier = match &config.grpc_client_ca {
+        Some(ca_path) => {
+            info!("Defguard Core client certificates will be verified with CA from {ca_path}");
+            Some(
+                client_verifier(ca_path, config.grpc_client_allowed_name.clone())
+                    .context("Failed to configure gRPC client certificate verification")?,
+            )
+        }
+        None => None,
+    };
+
+    // Start background task to evict expired requests to Defguard Core
+    tokio::spawn(grpc_server.clone().run_pending_cleanup());
+    if config.core_stale_timeout > 0 {
+        tokio::spawn(
+            grpc_server
+                .clone()
+                .run_stale_check(Duration::from_secs(config.core_stale_timeout)),
+        );
+    }
 
     // Start gRPC server.
     debug!("Spawning gRPC server");
-    tasks.spawn(async move {
-        let addr = SocketAddr::new(
-            config
-                .grpc_bind_address
-                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
-            config.grpc_port,
+    let grpc_admission =
+        AdmissionInterceptor::new(config.grpc_allowed_cidrs.clone(), config.grpc_token.clone());
+    let grpc_bind_address = config.grpc_bind_address;
+    let grpc_port = config.grpc_port;
+    // PROXY protocol header is only accepted from trusted proxies
+    let grpc_proxy_protocol = config
+        .grpc_proxy_protocol
+        .then(|| TrustedPeers::new(config.trusted_proxies.clone()));
+    let grpc_tls = grpc_cert_resolver.clone();
+    let grpc_keepalive_interval = (config.grpc_keepalive_interval > 0)
+        .then(|| Duration::from_secs(config.grpc_keepalive_interval));
+    let grpc_keepalive_timeout = Duration::from_secs(config.grpc_keepalive_timeout);
+    let core_url = config.core_url.clone();
+    let core_tls = match &core_url {
+        Some(url) if url.scheme() == "https" => Some(client_config(
+            config.core_ca.as_deref(),
+            config.grpc_cert.as_deref().zip(config.grpc_key.as_deref()),
+            vec![b"h2".to_vec()],
+        )?),
+        _ => None,
+    };
+    let (health_reporter, health_service) = health_reporter();
+    tokio::spawn(grpc_server.clone().run_health_reporter(health_reporter));
+    let mut proxy_service = proxy_server::ProxyServer::new(grpc_server.clone())
+        .max_encoding_message_size(config.grpc_max_encoding_message_size)
+        .max_decoding_message_size(config.grpc_max_decoding_message_size);
+    if let Some(compression) = config.grpc_send_compression {
+        proxy_service = proxy_service.send_compressed(compression.into());
+    }
+    for compression in &config.grpc_accept_compression {
+        proxy_service = proxy_service.accept_compressed((*compression).into());
+    }
+    let own_version = Version::parse(VERSION)?;
+    let versioned_service = ServiceBuilder::new()
+        .layer(tonic::service::InterceptorLayer::new(grpc_admission))
+        .layer(tonic::service::InterceptorLayer::new(
+            DefguardVersionInterceptor::new(
+                own_version.clone(),
+                DefguardComponent::Core,
+                MIN_CORE_VERSION,
+                false,
+            ),
+        ))
+        .layer(DefguardVersionLayer::new(own_version))
+        .service(proxy_service);
+    let mut grpc_routes = Routes::new(versioned_service).add_service(health_service);
+    if config.grpc_reflection {
+        info!("gRPC server reflection is enabled");
+        grpc_routes = grpc_routes.add_service(reflection_service()?);
+    }
+    // In single port mode gRPC requests are routed to the services by the API server.
+    let grpc_router = config
+        .grpc_single_port
+        .then(|| grpc_routes.clone().into_axum_router());
+    if grpc_router.is_some() {
+        info!(
+            "gRPC server is sharing the API web server port {}",
+            config.http_port
         );
-        info!("gRPC server is listening on {addr}");
-        let mut builder = if let (Some(cert), Some(key)) = (grpc_cert, grpc_key) {
-            let identity = Identity::from_pem(cert, key);
-            Server::builder().tls_config(ServerTlsConfig::new().identity(identity))?
-        } else {
-            Server::builder()
-        };
-        let own_version = Version::parse(VERSION)?;
-        let versioned_service = ServiceBuilder::new()
-            .layer(tonic::service::InterceptorLayer::new(
-                DefguardVersionInterceptor::new(
-                    own_version.clone(),
-                    DefguardComponent::Core,
-                    MIN_CORE_VERSION,
-                    false,
-                ),
-            ))
-            .layer(DefguardVersionLayer::new(own_version))
-            .service(proxy_server::ProxyServer::new(grpc_server));
-        builder
-            .add_service(versioned_service)
-            .serve(addr)
-            .await
+    } else if let Some(url) = core_url {
+        let connector = CoreConnector::new(url.clone(), core_tls, grpc_server.clone())?
+            .with_keepalive(grpc_keepalive_interval, grpc_keepalive_timeout)
+            .with_compression(
+                config.grpc_send_compression.map(Into::into),
+                config
+                    .grpc_accept_compression
+                    .iter()
+                    .map(|compression| (*compression).into())
+                    .collect(),
+            )
+            .with_max_message_size(
+                config.grpc_max_encoding_message_size,
+                config.grpc_max_decoding_message_size,
+            );
+        info!("Connecting to Defguard Core at {url}");
+        let core_shutdown = core_shutdown.clone();
+        tasks.spawn(async move {
+            core_shutdown.run_until_cancelled(connector.run()).await;
+            Ok(())
+        });
+    } else {
+        let core_shutdown = core_shutdown.clone().cancelled_owned();
+        tasks.spawn(async move {
+            let addr = SocketAddr::new(
+                grpc_bind_address.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+                grpc_port,
+            );
+            let router = Server::builder()
+                .http2_keepalive_interval(grpc_keepalive_interval)
+                .http2_keepalive_timeout(Some(grpc_keepalive_timeout))
+                .add_routes(grpc_routes);
+            if let Some(resolver) = grpc_tls {
+                let tls_config = server_config(
+                    resolver,
+                    vec![b"h2".to_vec()],
+                    TlsVersion::Tls12,
+                    grpc_client_verifier,
+                )?;
+                let listener = TcpListener::bind(addr).await?;
+                info!("gRPC server is listening on {addr} (TLS)");
+                router
+                    .serve_with_incoming_shutdown(
+                        tls_incoming(
+                            listener,
+                            TlsAcceptor::from(Arc::new(tls_config)),
+                            grpc_proxy_protocol,
+                        ),
+                        core_shutdown,
+                    )
+                    .await
+            } else if let Some(trusted_peers) = grpc_proxy_protocol {
+                let listener = TcpListener::bind(addr).await?;
+                info!("gRPC server is listening on {addr} (PROXY protocol)");
+                router
+                    .serve_with_incoming_shutdown(
+                        proxied_incoming(listener, trusted_peers),
+                        core_shutdown,
+                    )
+                    .await
+            } else {
+                info!("gRPC server is listening on {addr}");
+                router.serve_with_shutdown(addr, core_shutdown).await
+            }
             .context("Error running gRPC server")
-    });
+        });
+    }
+
+    if config.trusted_proxies.is_empty() {
+        info!(
+            "No trusted_proxies configured, forwarding headers are ignored and client addresses \
+            are taken from connections"
+        );
+        if config.lockout_threshold > 0 {
+            warn!(
+                "Lockout is enabled without trusted_proxies, behind a reverse proxy all clients \
+                share its address and are locked out together"
+            );
+        }
+    }
 
     // Setup tower_governor rate-limiter
-    debug!(
-        "Configuring rate limiter, per_second: {}, burst: {}",
-        config.rate_limit_per_second, config.rate_limit_burst
+    let rate_limiter = RateLimiter::new(
+        config.rate_limit_per_second,
+        config.rate_limit_burst,
+        &config.rate_limit_policies,
     );
-    let governor_conf = GovernorConfigBuilder::default()
-        .key_extractor(SmartIpKeyExtractor)
-        .per_second(config.rate_limit_per_second)
-        .burst_size(config.rate_limit_burst)
-        .finish();
+    // Start background task to cleanup rate-limiter data
+    tokio::spawn(rate_limiter.clone().run_cleanup());
+    tokio::spawn(lockout.clone().run_cleanup());
 
-    let governor_conf = if let Some(conf) = governor_conf {
-        let governor_limiter = conf.limiter().clone();
-
-        // Start background task to cleanup rate-limiter data
-        tokio::spawn(async move {
-            loop {
-                tokio::time::sleep(RATE_LIMITER_CLEANUP_PERIOD).await;
-                debug!(
-                    "Cleaning-up rate limiter storage, current size: {}",
-                    governor_limiter.len()
-                );
-                governor_limiter.retain_recent();
-            }
-        });
-        info!(
-            "Configured rate limiter, per_second: {}, burst: {}",
-            config.rate_limit_per_second, config.rate_limit_burst
+    // Prometheus metrics are served by the API server, unless a separate port is configured
+    let mut metrics_routes = Router::new();
+    if let (Some(reader), None) = (&metrics, config.metrics_port) {
+        metrics_routes = metrics_routes.route(
+            "/metrics",
+            get(prometheus_metrics).with_state(reader.clone()),
         );
-        Some(conf)
-    } else {
-        info!("Skipping rate limiter setup");
-        None
-    };
+    }
 
     // Build axum app
-    let mut app = Router::new()
+    let app = Router::new()
         .route("/", get(index))
         .route("/{*path}", get(index))
         .route("/fonts/{*path}", get(web_asset))
@@ -283,59 +655,439 @@ pub async fn run_server(config: Config) -> anyhow::Result<()> {
                 .route("/poll", post(polling::info))
                 .route("/health", get(healthcheck))
                 .route("/health-grpc", get(healthcheckgrpc))
+                .route("/health-tls", get(healthchecktls))
+                .route("/health-grpc-queues", get(healthcheckgrpcqueues))
                 .route("/info", get(app_info)),
         )
+        .merge(metrics_routes)
         .fallback_service(get(handle_404))
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            lockout_middleware,
+        ))
         .layer(middleware::map_response(powered_by_header))
         .layer(middleware::from_fn_with_state(
             shared_state.clone(),
             core_version_middleware,
         ))
+        .layer(middleware::from_fn_with_state(
+            shared_state.cookie_keys.clone(),
+            reencrypt_cookies_middleware,
+        ))
         .layer(DefguardVersionLayer::new(Version::parse(VERSION)?))
         .with_state(shared_state)
+        .layer(middleware::from_fn_with_state(
+            rate_limiter.clone(),
+            rate_limit_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            shutdown.clone(),
+            track_requests_middleware,
+        ))
+        .layer(middleware::from_fn(http_metrics_middleware))
+        // inside `TraceLayer`, so trace context is taken from the request span
+        .layer(middleware::from_fn(request_context_middleware))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(|request: &Request<Body>| {
                     let addr = get_client_addr(request);
-                    info_span!(
+                    let span = info_span!(
                         "http_request",
                         method = ?request.method(),
                         path = ?request.uri(),
                         // TODO: headers only in debug logs
                         // headers = ?request.headers(),
                         client_addr = addr,
-                    )
+                        // recorded by `request_context_middleware`
+                        request_id = field::Empty,
+                    );
+                    // continue trace started upstream in exported spans
+                    let parent =
+                        TraceContextPropagator::new().extract(&HeaderExtractor(request.headers()));
+                    let _ = span.set_parent(parent);
+                    span
                 })
                 .on_response(trace::DefaultOnResponse::new().level(Level::DEBUG)),
-        );
-    if let Some(conf) = governor_conf {
-        app = app.layer(GovernorLayer::new(conf));
-    }
+        )
+        .layer(middleware::from_fn_with_state(
+            ClientIpResolver::new(config.trusted_proxies.clone(), config.trusted_proxy_header),
+            client_ip_middleware,
+        ));
     debug!("Configured API server routing: {app:?}");
+    let app = match grpc_router {
+        Some(grpc_router) => multiplex(app, grpc_router),
+        None => app,
+    };
 
     // Start web server.
     debug!("Spawning API web server");
-    tasks.spawn(async move {
+    let http_bind_address = config
+        .http_bind_address
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let http_port = config.http_port;
+    let http_proxy_protocol = config
+        .http_proxy_protocol
+        .then(|| TrustedPeers::new(config.trusted_proxies.clone()));
+    let http_keepalive = config
+        .grpc_single_port
+        .then_some((grpc_keepalive_interval, grpc_keepalive_timeout));
+    let http_tls = http_cert_resolver
+        .clone()
+        .map(|resolver| {
+            server_config(
+                resolver,
+                vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+                config.http_tls_min_version,
+                None,
+            )
+        })
+        .transpose()?;
+    let redirect_proxy_protocol = http_proxy_protocol.clone();
+    let stopped = shutdown.clone().started();
+    http_tasks.spawn(async move {
+        let addr = SocketAddr::new(http_bind_address, http_port);
+        let listener = TcpListener::bind(&addr).await?;
+        if let Some(tls_config) = http_tls {
+            info!("API web server is listening on {addr} (TLS)");
+            let listener = TlsListener::new(
+                listener,
+                TlsAcceptor::from(Arc::new(tls_config)),
+                http_proxy_protocol,
+            )?;
+            serve_api(listener, app, http_keepalive, stopped).await
+        } else if let Some(trusted_peers) = http_proxy_protocol {
+            info!("API web server is listening on {addr} (PROXY protocol)");
+            let listener = ProxiedListener::new(listener, trusted_peers)?;
+            serve_api(listener, app, http_keepalive, stopped).await
+        } else {
+            info!("API web server is listening on {addr}");
+            serve_api(listener, app, http_keepalive, stopped).await
+        }
+        .context("Error running HTTP server")
+    });
+
+    // Start plain HTTP server redirecting to HTTPS.
+    if let (Some(redirect_port), Some(_)) = (config.http_redirect_port, &http_cert_resolver) {
+        let default_host = config.url.host_str().unwrap_or("localhost").to_string();
+        let stopped = shutdown.clone().started();
+        http_tasks.spawn(async move {
+            let addr = SocketAddr::new(http_bind_address, redirect_port);
+            let listener = TcpListener::bind(&addr).await?;
+            info!("HTTP redirect server is listening on {addr}");
+            let app = Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+                Redirect::permanent(&https_redirect_url(
+                    &headers,
+                    &uri,
+                    &default_host,
+                    http_port,
+                ))
+            });
+            if let Some(trusted_peers) = redirect_proxy_protocol {
+                serve(ProxiedListener::new(listener, trusted_peers)?, app)
+                    .with_graceful_shutdown(stopped)
+                    .await
+            } else {
+                serve(listener, app).with_graceful_shutdown(stopped).await
+            }
+            .context("Error running HTTP redirect server")
+        });
+    }
+
+    // Start metrics server.
+    if let (Some(reader), Some(metrics_port)) = (metrics, config.metrics_port) {
         let addr = SocketAddr::new(
             config
-                .http_bind_address
+                .metrics_bind_address
                 .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
-            config.http_port,
+            metrics_port,
         );
-        let listener = TcpListener::bind(&addr).await?;
-        info!("API web server is listening on {addr}");
-        serve(
-            listener,
-            app.into_make_service_with_connect_info::<SocketAddr>(),
-        )
-        .await
-        .context("Error running HTTP server")
-    });
+        let stopped = shutdown.clone().started();
+        http_tasks.spawn(async move {
+            let listener = TcpListener::bind(&addr).await?;
+            info!("Metrics server is listening on {addr}");
+            let app = Router::new()
+                .route("/metrics", get(prometheus_metrics))
+                .with_state(reader);
+            serve(listener, app)
+                .with_graceful_shutdown(stopped)
+                .await
+                .context("Error running metrics server")
+        });
+    }
+
+    // Reload configuration on SIGHUP.
+    let reload_handles = ReloadHandles {
+        log_filter,
+        rate_limiter,
+        lockout,
+        grpc_cert_resolver,
+        http_cert_resolver,
+        url,
+    };
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout);
+    let reload_handler = tasks.spawn(run_reload_handler(config, reload_handles));
 
     info!("Defguard Proxy server initialization complete");
-    while let Some(Ok(result)) = tasks.join_next().await {
-        result?;
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    let signal = loop {
+        tokio::select! {
+            Some(result) = tasks.join_next() => result??,
+            Some(result) = http_tasks.join_next() => result??,
+            signal = &mut signal => break signal?,
+        }
+    };
+
+    info!(
+        "Received {signal}, shutting down, waiting up to {}s for in-flight requests",
+        shutdown_timeout.as_secs()
+    );
+    reload_handler.abort();
+    shutdown.start();
+    let drain = async {
+        shutdown.tracked_done().await;
+        debug!("In-flight requests are done, closing Defguard Core connections");
+        grpc_server.close_streams();
+        core_shutdown.cancel();
+        while let Some(result) = http_tasks.join_next().await {
+            log_stopped_task(result);
+        }
+        while let Some(result) = tasks.join_next().await {
+            log_stopped_task(result);
+        }
+    };
+    tokio::select! {
+        result = timeout(shutdown_timeout, drain) => match result {
+            Ok(()) => info!("Defguard Proxy server stopped"),
+            Err(_) => warn!(
+                "In-flight requests didn't finish within {}s, dropping remaining connections",
+                shutdown_timeout.as_secs()
+            ),
+        },
+        signal = shutdown_signal() => {
+            warn!("Received {} during shutdown, dropping remaining connections", signal?);
+        }
     }
 
     Ok(())
 }
+
+// Log errors of tasks stopped during shutdown.
+fn log_stopped_task(result: Result<anyhow::Result<()>, JoinError>) {
+    match result {
+        Ok(Ok(())) => (),
+        Ok(Err(err)) => error!("{err:#}"),
+        Err(err) if err.is_cancelled() => (),
+        Err(err) => error!("Task failed during shutdown: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use axum::{
+        body::{to_bytes, Bytes},
+        Extension,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use tonic::{codec::Codec, Streaming};
+    use tonic_health::pb::{
+        health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+    };
+    use tonic_prost::ProstCodec;
+    use tonic_reflection::pb::v1::{
+        server_reflection_client::ServerReflectionClient,
+        server_reflection_request::MessageRequest, server_reflection_response::MessageResponse,
+        ServerReflectionRequest,
+    };
+
+    use super::*;
+    use crate::{
+        config::CoreSelection,
+        proto::{CoreRequest, CoreResponse},
+    };
+
+    #[tokio::test]
+    async fn test_grpc_health_and_reflection() {
+        let grpc_server = ProxyServer::new(CoreSelection::Primary, 8.try_into().unwrap());
+        let (health_reporter, health_service) = health_reporter();
+        tokio::spawn(grpc_server.clone().run_health_reporter(health_reporter));
+        let app = Routes::new(health_service)
+            .add_service(reflection_service().unwrap())
+            .into_axum_router();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { serve(listener, app).await });
+        let channel = tonic::transport::Endpoint::from_shared(url)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+
+        // health follows Defguard Core connection
+        let mut health = HealthClient::new(channel.clone());
+        let mut statuses = health
+            .watch(HealthCheckRequest {
+                service: "defguard.proxy.Proxy".into(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let status = statuses.message().await.unwrap().unwrap().status();
+        assert_eq!(status, ServingStatus::NotServing);
+        // stream of a connected core which never responds
+        let (tx, _rx) = grpc_server.request_channel();
+        let responses =
+            Body::from_stream(futures_util::stream::pending::<Result<Bytes, Infallible>>());
+        let _stream = grpc_server.add_core_stream(
+            SocketAddr::from(([127, 0, 0, 1], 1)),
+            Version::new(1, 6, 0),
+            tx,
+            Streaming::new_request(
+                ProstCodec::<CoreRequest, CoreResponse>::default().decoder(),
+                responses,
+                None,
+                None,
+            ),
+        );
+        let status = statuses.message().await.unwrap().unwrap().status();
+        assert_eq!(status, ServingStatus::Serving);
+
+        // reflection lists gRPC services
+        let mut reflection = ServerReflectionClient::new(channel);
+        let request = ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(MessageRequest::ListServices(String::new())),
+        };
+        let response = reflection
+            .server_reflection_info(tokio_stream::once(request))
+            .await
+            .unwrap()
+            .into_inner()
+            .message()
+            .await
+            .unwrap()
+            .unwrap();
+        let Some(MessageResponse::ListServicesResponse(list)) = response.message_response else {
+            panic!("unexpected reflection response: {response:?}");
+        };
+        let mut services: Vec<_> = list
+            .service
+            .into_iter()
+            .map(|service| service.name)
+            .collect();
+        services.sort();
+        assert_eq!(
+            services,
+            [
+                "defguard.proxy.Proxy",
+                "grpc.health.v1.Health",
+                "grpc.reflection.v1.ServerReflection"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multiplex() {
+        let app = Router::new().route("/{*path}", post(|| async { "api" }));
+        let grpc = Router::new().route(
+            "/{*path}",
+            post(|Extension(info): Extension<TcpConnectInfo>| async move {
+                format!("grpc {}", info.remote_addr.unwrap())
+            }),
+        );
+        let app = multiplex(app, grpc);
+        let addr = SocketAddr::from(([192, 0, 2, 1], 4000));
+        let call = |content_type: &'static str| {
+            let mut request = Request::post("/defguard.proxy.Proxy/Bidi")
+                .header(CONTENT_TYPE, content_type)
+                .body(Body::empty())
+                .unwrap();
+            request.extensions_mut().insert(ConnectInfo(addr));
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                to_bytes(response.into_body(), usize::MAX).await.unwrap()
+            }
+        };
+
+        // gRPC requests are routed to gRPC services, with client address
+        assert_eq!(call("application/grpc").await, "grpc 192.0.2.1:4000");
+        assert_eq!(call("application/grpc+proto").await, "grpc 192.0.2.1:4000");
+        // other requests are handled by the API server
+        assert_eq!(call("application/json").await, "api");
+        assert_eq!(call("text/plain; application/grpc").await, "api");
+    }
+
+    #[tokio::test]
+    async fn test_serve_with_keepalive() {
+        let app = Router::new().route(
+            "/ip",
+            get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move { addr.ip().to_string() }),
+        );
+        let (_health_reporter, health_service) = health_reporter();
+        let app = multiplex(app, Routes::new(health_service).into_axum_router());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_with_keepalive(
+            listener,
+            app,
+            Some(Duration::from_millis(100)),
+            Duration::from_millis(200),
+            async move {
+                let _ = stopped.await;
+            },
+        ));
+
+        // HTTP/1 requests get the client address
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /ip HTTP/1.1\r\nHost: proxy\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("127.0.0.1"));
+
+        // gRPC is served over HTTP/2
+        let channel = tonic::transport::Endpoint::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let status = HealthClient::new(channel)
+            .check(HealthCheckRequest::default())
+            .await
+            .unwrap()
+            .into_inner()
+            .status();
+        assert_eq!(status, ServingStatus::Serving);
+
+        // HTTP/2 connection not acknowledging pings is closed
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+            .await
+            .unwrap();
+        let mut frames = Vec::new();
+        timeout(Duration::from_secs(5), stream.read_to_end(&mut frames))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // server stops after shutdown
+        stop.send(()).unwrap();
+        timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+}
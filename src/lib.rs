@@ -1,16 +1,34 @@
 use defguard_version::Version;
 
+mod admission;
 pub mod assets;
+pub mod cli;
+mod client_ip;
 pub mod config;
+mod cookies;
 mod enterprise;
 mod error;
 mod grpc;
 mod handlers;
 pub mod http;
+mod lockout;
 pub mod logging;
+pub mod metrics;
+mod outbound;
+mod pending;
+mod proxy_protocol;
+mod ratelimit;
+mod reload;
+mod request_id;
+mod shutdown;
+pub mod telemetry;
+mod tls;
 
 pub(crate) mod proto {
     tonic::include_proto!("defguard.proxy");
+
+    pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("proxy_descriptor");
 }
 
 #[macro_use]
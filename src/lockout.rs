@@ -0,0 +1,456 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::BuildHasher,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header::RETRY_AFTER, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::extract::PrivateCookieJar;
+use serde_json::json;
+
+use crate::{
+    client_ip::ClientIp,
+    http::ENROLLMENT_COOKIE_NAME,
+    metrics::metrics,
+    ratelimit::{body_fields, route_matches},
+};
+
+const LOCKOUT_CLEANUP_PERIOD: Duration = Duration::from_secs(60);
+// Routes where failed authentication attempts are counted.
+const LOCKOUT_ROUTES: &[&str] = &[
+    "/api/v1/client-mfa",
+    "/api/v1/enrollment",
+    "/api/v1/openid/callback",
+];
+const TOKEN_FIELD: &str = "token";
+// Maximum number of client IP addresses and tokens tracked at once.
+const MAX_TRACKED_KEYS: usize = 100_000;
+// Number of entries evicted at once when the limit is reached.
+const EVICTED_KEYS: usize = MAX_TRACKED_KEYS / 10;
+
+/// Marks responses to failed authentication attempts, counted by `lockout_middleware`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct AuthFailure;
+
+/// Source of authentication attempts. Tokens are secret and can be arbitrarily long, so only
+/// their keyed hash is kept.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+enum LockoutKey {
+    Ip(IpAddr),
+    Token(u64),
+}
+
+impl fmt::Display for LockoutKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "client {ip}"),
+            Self::Token(hash) => write!(f, "token #{hash:016x}"),
+        }
+    }
+}
+
+struct Attempts {
+    failures: u32,
+    since: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Attempts {
+    fn is_locked(&self, now: Instant) -> bool {
+        self.locked_until.is_some_and(|until| until > now)
+    }
+}
+
+struct LockoutState {
+    threshold: u32,
+    window: Duration,
+    duration: Duration,
+    attempts: HashMap<LockoutKey, Attempts>,
+}
+
+impl LockoutState {
+    // Keeps at most `cap` entries, evicting the oldest ones which are not locked out first.
+    fn evict(&mut self, now: Instant, cap: usize) {
+        let count = self.attempts.len().saturating_sub(cap);
+        if count == 0 {
+            return;
+        }
+        let mut order: Vec<_> = self
+            .attempts
+            .iter()
+            .map(|(key, attempts)| (attempts.is_locked(now), attempts.since, *key))
+            .collect();
+        // entries with equal timestamps are evicted in arbitrary order, exactly `count` of them
+        order.select_nth_unstable_by_key(count - 1, |(locked, since, _)| (*locked, *since));
+        for (_, _, key) in &order[..count] {
+            self.attempts.remove(key);
+        }
+        warn!(
+            "Tracked {MAX_TRACKED_KEYS} clients and tokens, evicted the oldest ones, {} left",
+            self.attempts.len()
+        );
+    }
+}
+
+/// Locks out client IP addresses and tokens after too many failed authentication attempts
+/// within a time window, like fail2ban does. Settings can be replaced at runtime.
+#[derive(Clone)]
+pub(crate) struct Lockout {
+    state: Arc<Mutex<LockoutState>>,
+    // keys token hashes, so they can't be predicted
+    hasher: RandomState,
+}
+
+impl Lockout {
+    #[must_use]
+    pub(crate) fn new(threshold: u32, window: u64, duration: u64) -> Self {
+        if threshold > 0 {
+            info!(
+                "Configured lockout after {threshold} failed authentication attempts within \
+                {window}s, for {duration}s"
+            );
+        }
+        Self {
+            state: Arc::new(Mutex::new(LockoutState {
+                threshold,
+                window: Duration::from_secs(window),
+                duration: Duration::from_secs(duration),
+                attempts: HashMap::new(),
+            })),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Apply new lockout settings. Counted attempts and current lockouts are kept.
+    pub(crate) fn update(&self, threshold: u32, window: u64, duration: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.threshold = threshold;
+        state.window = Duration::from_secs(window);
+        state.duration = Duration::from_secs(duration);
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.state.lock().unwrap().threshold > 0
+    }
+
+    fn token_key(&self, token: &str) -> LockoutKey {
+        LockoutKey::Token(self.hasher.hash_one(token))
+    }
+
+    // Time until all of `keys` are unlocked, if any is locked.
+    fn locked_for(&self, keys: &[LockoutKey]) -> Option<Duration> {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap();
+        keys.iter()
+            .filter_map(|key| state.attempts.get(key)?.locked_until)
+            .filter(|until| *until > now)
+            .max()
+            .map(|until| until - now)
+    }
+
+    fn record_failure(&self, keys: &[LockoutKey]) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let (threshold, window, duration) = (state.threshold, state.window, state.duration);
+        for key in keys {
+            if state.attempts.len() >= MAX_TRACKED_KEYS && !state.attempts.contains_key(key) {
+                state.evict(now, MAX_TRACKED_KEYS - EVICTED_KEYS);
+            }
+            let attempts = state.attempts.entry(*key).or_insert(Attempts {
+                failures: 0,
+                since: now,
+                locked_until: None,
+            });
+            if now.duration_since(attempts.since) > window {
+                attempts.failures = 0;
+                attempts.since = now;
+            }
+            attempts.failures += 1;
+            if attempts.failures >= threshold {
+                warn!(
+                    "Locking out {key} for {}s after {} failed authentication attempts",
+                    duration.as_secs(),
+                    attempts.failures
+                );
+                metrics().lockouts.add(1, &[]);
+                attempts.failures = 0;
+                attempts.since = now;
+                attempts.locked_until = Some(now + duration);
+            }
+        }
+    }
+
+    /// Periodically remove expired attempts and lockouts.
+    pub(crate) async fn run_cleanup(self) {
+        loop {
+            tokio::time::sleep(LOCKOUT_CLEANUP_PERIOD).await;
+            let now = Instant::now();
+            let mut state = self.state.lock().unwrap();
+            let window = state.window;
+            state.attempts.retain(|key, attempts| {
+                if attempts.locked_until.is_some_and(|until| until <= now) {
+                    info!("Lockout of {key} expired");
+                    attempts.locked_until = None;
+                }
+                attempts.locked_until.is_some()
+                    || (attempts.failures > 0 && now.duration_since(attempts.since) <= window)
+            });
+        }
+    }
+}
+
+// Token the request authenticates with, from JSON body or query, or the enrollment session.
+async fn request_token(
+    request: Request,
+    cookies: &PrivateCookieJar,
+) -> Result<(Request, Option<String>), Response<Body>> {
+    let (request, fields) = body_fields(request, &[TOKEN_FIELD.to_string()]).await?;
+    let token = fields
+        .into_iter()
+        .flatten()
+        .next()
+        .or_else(|| {
+            url::form_urlencoded::parse(request.uri().query()?.as_bytes())
+                .find(|(name, _)| name == TOKEN_FIELD)
+                .map(|(_, value)| value.into_owned())
+        })
+        .or_else(|| {
+            cookies
+                .get(ENROLLMENT_COOKIE_NAME)
+                .map(|cookie| cookie.value().to_string())
+        })
+        .filter(|token| !token.is_empty());
+    Ok((request, token))
+}
+
+/// Count failed authentication attempts on MFA, enrollment and OpenID callback routes,
+/// rejecting requests from locked out client IP addresses and tokens with 429.
+pub(crate) async fn lockout_middleware(
+    State(lockout): State<Lockout>,
+    cookies: PrivateCookieJar,
+    request: Request,
+    next: Next,
+) -> Response<Body> {
+    let path = request.uri().path();
+    if !lockout.is_enabled()
+        || !LOCKOUT_ROUTES
+            .iter()
+            .any(|route| route_matches(route, path))
+    {
+        return next.run(request).await;
+    }
+    let Some(ClientIp(ip)) = request.extensions().get::<ClientIp>().copied() else {
+        return next.run(request).await;
+    };
+    let (request, token) = match request_token(request, &cookies).await {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let mut keys = vec![LockoutKey::Ip(ip)];
+    keys.extend(token.map(|token| lockout.token_key(&token)));
+
+    if let Some(locked_for) = lockout.locked_for(&keys) {
+        debug!("Rejecting request from locked out client {ip}");
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, (locked_for.as_secs() + 1).to_string())],
+            Json(json!({"error": "Too many failed attempts, try again later"})),
+        )
+            .into_response();
+    }
+    let response = next.run(request).await;
+    if response.extensions().get::<AuthFailure>().is_some() {
+        lockout.record_failure(&keys);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        extract::FromRef,
+        middleware,
+        routing::{get, post},
+        Router,
+    };
+    use axum_extra::extract::cookie::Key;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::error::ApiError;
+
+    #[derive(Clone)]
+    struct TestState {
+        lockout: Lockout,
+        key: Key,
+    }
+
+    impl FromRef<TestState> for Lockout {
+        fn from_ref(state: &TestState) -> Self {
+            state.lockout.clone()
+        }
+    }
+
+    impl FromRef<TestState> for Key {
+        fn from_ref(state: &TestState) -> Self {
+            state.key.clone()
+        }
+    }
+
+    async fn finish(body: String) -> Result<(), ApiError> {
+        if body.contains("bad") {
+            Err(ApiError::Unauthorized("invalid code".into()))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn status(app: &Router, path: &str, ip: [u8; 4], body: &str) -> StatusCode {
+        let request = Request::post(path)
+            .extension(ClientIp(ip.into()))
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_lockout() {
+        let lockout = Lockout::new(2, 60, 60);
+        let state = TestState {
+            lockout: lockout.clone(),
+            key: Key::generate(),
+        };
+        let app = Router::new()
+            .route("/api/v1/client-mfa/finish", post(finish))
+            .route("/api/v1/password-reset/reset", post(finish))
+            .route("/api/v1/enrollment/start", get(|| async {}))
+            .layer(middleware::from_fn_with_state(state, lockout_middleware));
+
+        let path = "/api/v1/client-mfa/finish";
+        let bad = r#"{"token":"token-1","code":"bad"}"#;
+        let good = r#"{"token":"token-1","code":"good"}"#;
+        assert_eq!(
+            status(&app, path, [1, 1, 1, 1], bad).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app, path, [2, 2, 2, 2], bad).await,
+            StatusCode::UNAUTHORIZED
+        );
+        // token is locked out, no matter the client IP address
+        assert_eq!(
+            status(&app, path, [3, 3, 3, 3], good).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // client IP addresses are not, each failed once
+        let other = r#"{"token":"token-2"}"#;
+        assert_eq!(
+            status(&app, path, [1, 1, 1, 1], other).await,
+            StatusCode::OK
+        );
+        // second failure from a client IP address locks it out
+        let bad = r#"{"token":"token-3","code":"bad"}"#;
+        assert_eq!(
+            status(&app, path, [1, 1, 1, 1], bad).await,
+            StatusCode::UNAUTHORIZED
+        );
+        let request = Request::get("/api/v1/enrollment/start?token=token-4")
+            .extension(ClientIp([1, 1, 1, 1].into()))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+
+        // other routes are not affected
+        let path = "/api/v1/password-reset/reset";
+        for _ in 0..3 {
+            assert_eq!(
+                status(&app, path, [1, 1, 1, 1], bad).await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+
+        // tokens are not logged
+        let key = lockout.token_key("token-1");
+        assert_eq!(key, lockout.token_key("token-1"));
+        assert!(!key.to_string().contains("token-1"));
+
+        // lockout is disabled with threshold 0
+        lockout.update(0, 60, 60);
+        assert_eq!(
+            status(&app, "/api/v1/client-mfa/finish", [1, 1, 1, 1], good).await,
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn test_tracked_keys_limit() {
+        let lockout = Lockout::new(1, 60, 60);
+        let locked = LockoutKey::Ip([1, 1, 1, 1].into());
+        lockout.record_failure(&[locked]);
+        lockout.update(100, 60, 60);
+        let oldest = LockoutKey::Ip([2, 2, 2, 2].into());
+        lockout.record_failure(&[oldest]);
+        let keys: Vec<_> = (2..MAX_TRACKED_KEYS)
+            .map(|n| lockout.token_key(&n.to_string()))
+            .collect();
+        for key in &keys {
+            lockout.record_failure(&[*key]);
+        }
+        assert_eq!(
+            lockout.state.lock().unwrap().attempts.len(),
+            MAX_TRACKED_KEYS
+        );
+
+        // oldest entries are evicted, except for lockouts
+        let newest = lockout.token_key("newest");
+        lockout.record_failure(&[newest]);
+        let state = lockout.state.lock().unwrap();
+        assert_eq!(state.attempts.len(), MAX_TRACKED_KEYS - EVICTED_KEYS + 1);
+        assert!(state.attempts.contains_key(&locked));
+        assert!(!state.attempts.contains_key(&oldest));
+        assert!(state.attempts.contains_key(&newest));
+        assert!(state.attempts.contains_key(keys.last().unwrap()));
+    }
+
+    #[test]
+    fn test_evict_equal_timestamps() {
+        let now = Instant::now();
+        let attempts = |locked_until| Attempts {
+            failures: 1,
+            since: now,
+            locked_until,
+        };
+        let mut state = LockoutState {
+            threshold: 1,
+            window: Duration::from_secs(60),
+            duration: Duration::from_secs(60),
+            attempts: (0..10)
+                .map(|n| (LockoutKey::Token(n), attempts(None)))
+                .collect(),
+        };
+        let locked = LockoutKey::Token(10);
+        state
+            .attempts
+            .insert(locked, attempts(Some(now + Duration::from_secs(60))));
+
+        // exactly the overflow is evicted, lockouts are kept
+        state.evict(now, 5);
+        assert_eq!(state.attempts.len(), 5);
+        assert!(state.attempts.contains_key(&locked));
+        // nothing is evicted below the limit
+        state.evict(now, 5);
+        assert_eq!(state.attempts.len(), 5);
+    }
+}
@@ -18,35 +18,63 @@ use tracing_subscriber::{
     },
     layer::SubscriberExt,
     registry::LookupSpan,
+    reload,
     util::SubscriberInitExt,
-    EnvFilter,
+    EnvFilter, Layer, Registry,
 };
 
+use crate::telemetry::Telemetry;
+
+// Spans and events exported over OTLP, independent of the log filter.
+const TELEMETRY_FILTER: &str = "warn,defguard_proxy=debug";
+
+/// Handle allowing to replace the log filter at runtime.
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+// Builds log filter from `DEFGUARD_PROXY_LOG_FILTER` env variable,
+// falling back to the specified log level.
+pub(crate) fn log_filter(level: &LevelFilter) -> EnvFilter {
+    EnvFilter::try_from_env("DEFGUARD_PROXY_LOG_FILTER")
+        .unwrap_or_else(|_| level.to_string().into())
+}
+
 // Initializes tracing with the specified log level and version information.
 // Allows fine-grained filtering with `EnvFilter` directives.
 // The directives are read from `DEFGUARD_PROXY_LOG_FILTER` env variable.
 // For more info read: <https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html>
-pub fn init_tracing(own_version: Version, level: &LevelFilter) -> Result<(), DefguardVersionError> {
+// Returns a handle which allows changing the filter when configuration is reloaded.
+// If `telemetry` is set, spans are also exported to OpenTelemetry collector.
+pub fn init_tracing(
+    own_version: Version,
+    level: &LevelFilter,
+    telemetry: Option<&Telemetry>,
+) -> Result<LogFilterHandle, DefguardVersionError> {
+    let (filter, handle) = reload::Layer::new(log_filter(level));
     tracing_subscriber::registry()
         .with(
-            EnvFilter::try_from_env("DEFGUARD_PROXY_LOG_FILTER")
-                .unwrap_or_else(|_| level.to_string().into()),
-        )
-        .with(VersionFieldLayer)
-        .with(
-            fmt::layer()
-                .event_format(HttpVersionFormatter::new(own_version))
-                .fmt_fields(VersionFilteredFields),
+            VersionFieldLayer
+                .and_then(
+                    fmt::layer()
+                        .event_format(HttpVersionFormatter::new(own_version))
+                        .fmt_fields(VersionFilteredFields),
+                )
+                .with_filter(filter),
         )
+        .with(telemetry.and_then(Telemetry::tracer).map(|tracer| {
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(EnvFilter::new(TELEMETRY_FILTER))
+        }))
         .init();
 
     info!("Tracing initialized");
-    Ok(())
+    Ok(handle)
 }
 
 /// Implements fail2ban-friendly log format with version suffixes.
 /// HTTP info (if available) is extracted from the specified tracing span. The format is as follows:
 /// TIMESTAMP LEVEL CLIENT_ADDR METHOD URI LOG_MESSAGE [VERSION_SUFFIXES] || TRACING_DATA
+/// Request ID of the HTTP request is included in TRACING_DATA.
 pub(crate) struct HttpVersionFormatter<'a> {
     span: &'a str,
     timer: SystemTime,
@@ -110,7 +138,13 @@ where
                 if let Some(fields) = extensions.get::<FormattedFields<N>>() {
                     if !fields.is_empty() {
                         match span_name {
-                            x if x == self.span => http_log = Some(format!("{fields}")),
+                            x if x == self.span => {
+                                let log = format!("{fields}");
+                                if let Some(request_id) = HttpFields::parse(&log).request_id {
+                                    let _ = write!(context_logs, " {{request_id={request_id}}}");
+                                }
+                                http_log = Some(log);
+                            }
                             _ => {
                                 let _ = write!(context_logs, " {{{fields}}}");
                             }
@@ -136,15 +170,14 @@ where
 
         // write http context log (ip, method, path)
         if let Some(log) = http_log {
-            let split: Vec<&str> = log.split(['=', ' ']).collect();
-            let method = split.get(1).unwrap_or(&"unknown");
-            let path = split.get(3).unwrap_or(&"unknown");
-
-            let addr = split.get(5).map(|s| s.replace('"', ""));
-            let ip = addr
-                .and_then(|s| s.split(':').next().map(ToString::to_string))
-                .unwrap_or("unknown".to_string());
-            write!(versioned_writer, "{ip} {method} {path} ")?;
+            let fields = HttpFields::parse(&log);
+            write!(
+                versioned_writer,
+                "{} {} {} ",
+                fields.client_addr.unwrap_or("unknown"),
+                fields.method.unwrap_or("unknown"),
+                fields.path.unwrap_or("unknown")
+            )?;
         }
 
         // write actual log message
@@ -157,3 +190,116 @@ where
         writeln!(versioned_writer)
     }
 }
+
+/// Fields of the HTTP request span, formatted as
+/// `method=GET path=/api/v1/info?a=b client_addr="192.0.2.1" request_id="ID"`.
+#[derive(Debug, Default, PartialEq)]
+struct HttpFields<'a> {
+    method: Option<&'a str>,
+    path: Option<&'a str>,
+    client_addr: Option<&'a str>,
+    request_id: Option<&'a str>,
+}
+
+impl<'a> HttpFields<'a> {
+    fn parse(fields: &'a str) -> Self {
+        let mut parsed = Self::default();
+        // Values contain no spaces: URIs are percent-encoded and request IDs are validated,
+        // but they may contain `=`, so fields are split at the first one.
+        for field in fields.split(' ') {
+            let Some((name, value)) = field.split_once('=') else {
+                continue;
+            };
+            let value = Some(value.trim_matches('"'));
+            match name {
+                "method" => parsed.method = value,
+                "path" => parsed.path = value,
+                "client_addr" => parsed.client_addr = value,
+                "request_id" => parsed.request_id = value,
+                _ => (),
+            }
+        }
+        parsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tracing::info_span;
+
+    use super::*;
+
+    // Formats `message` logged in HTTP request span from `client_addr`.
+    fn format_http_log(client_addr: &str, message: &str) -> String {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let writer = Arc::clone(&output);
+        let subscriber = tracing_subscriber::registry().with(
+            fmt::layer()
+                .event_format(HttpVersionFormatter::new(Version::new(1, 6, 0)))
+                .with_ansi(false)
+                .with_writer(move || SharedBuffer(Arc::clone(&writer))),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            info_span!(
+                "http_request",
+                method = "GET",
+                path = "/api/v1/info",
+                client_addr,
+                request_id = "req-1",
+            )
+            .in_scope(|| info!("{message}"));
+        });
+        let output = output.lock().unwrap();
+        String::from_utf8(output.clone()).unwrap()
+    }
+
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_format_http_log() {
+        let log = format_http_log("192.0.2.1", "Enrollment started");
+        assert!(log.contains(" 192.0.2.1 GET /api/v1/info Enrollment started"));
+        assert!(log.contains("{request_id=req-1}"));
+        // IPv6 addresses are logged whole
+        let log = format_http_log("2001:db8::1", "Enrollment started");
+        assert!(log.contains(" 2001:db8::1 GET /api/v1/info Enrollment started"));
+    }
+
+    #[test]
+    fn test_parse_http_fields() {
+        assert_eq!(
+            HttpFields::parse(
+                "method=GET path=/api/v1/openid/callback?code=a=&state=b \
+                client_addr=\"192.0.2.1\" request_id=\"req-1\""
+            ),
+            HttpFields {
+                method: Some("GET"),
+                path: Some("/api/v1/openid/callback?code=a=&state=b"),
+                client_addr: Some("192.0.2.1"),
+                request_id: Some("req-1"),
+            }
+        );
+        // request ID is only recorded if present
+        assert_eq!(
+            HttpFields::parse("method=POST path=/api/v1/info client_addr=\"192.0.2.1\""),
+            HttpFields {
+                method: Some("POST"),
+                path: Some("/api/v1/info"),
+                client_addr: Some("192.0.2.1"),
+                request_id: None,
+            }
+        );
+    }
+}
@@ -1,4 +1,7 @@
-use defguard_proxy::{config::get_config, http::run_server, logging::init_tracing, VERSION};
+use defguard_proxy::{
+    cli::run_command, config::get_config, http::run_server, logging::init_tracing,
+    telemetry::Telemetry, VERSION,
+};
 use defguard_version::Version;
 
 #[tokio::main]
@@ -9,12 +12,27 @@ async fn main() -> anyhow::Result<()> {
     }
 
     let config = get_config()?;
-    init_tracing(Version::parse(VERSION)?, &config.log_level)?;
+    if let Some(command) = &config.command {
+        return run_command(command, &config);
+    }
+
+    let telemetry = Telemetry::from_config(&config)?;
+    let log_filter = init_tracing(
+        Version::parse(VERSION)?,
+        &config.log_level,
+        telemetry.as_ref(),
+    )?;
+    config.log_sources();
     // read config from env
     tracing::info!("Starting ... version v{}", VERSION);
 
     // run API web server
-    run_server(config).await?;
+    let metrics = telemetry.as_ref().and_then(Telemetry::prometheus);
+    let result = run_server(config, log_filter, metrics).await;
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
+    }
+    result?;
 
     Ok(())
 }
@@ -0,0 +1,344 @@
+use std::{
+    fmt::{self, Write},
+    sync::{Arc, LazyLock, Weak},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, State},
+    http::{header::CONTENT_TYPE, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use opentelemetry::{
+    global,
+    metrics::{Counter, Histogram, UpDownCounter},
+    KeyValue,
+};
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    metrics::{
+        data::{AggregatedMetrics, Metric, MetricData, ResourceMetrics},
+        reader::MetricReader,
+        InstrumentKind, ManualReader, Pipeline, Temporality,
+    },
+};
+
+use crate::proto::core_request;
+
+// Histogram buckets for durations in seconds.
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Instruments recording proxy behaviour. They're created from the global meter provider, which
+/// has to be installed before the first use, otherwise nothing is recorded.
+pub(crate) struct Metrics {
+    pub(crate) http_request_duration: Histogram<f64>,
+    pub(crate) core_request_duration: Histogram<f64>,
+    pub(crate) core_timeouts: Counter<u64>,
+    pub(crate) rate_limit_rejections: Counter<u64>,
+    pub(crate) lockouts: Counter<u64>,
+    pub(crate) remote_mfa_sessions: UpDownCounter<i64>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let meter = global::meter("defguard-proxy");
+        Self {
+            http_request_duration: meter
+                .f64_histogram("http.server.request.duration")
+                .with_description("Duration of HTTP requests")
+                .with_unit("s")
+                .with_boundaries(DURATION_BUCKETS.to_vec())
+                .build(),
+            core_request_duration: meter
+                .f64_histogram("defguard_proxy.core.request.duration")
+                .with_description("Time from sending a request to Defguard Core to its response")
+                .with_unit("s")
+                .with_boundaries(DURATION_BUCKETS.to_vec())
+                .build(),
+            core_timeouts: meter
+                .u64_counter("defguard_proxy.core.timeouts")
+                .with_description("Requests Defguard Core didn't respond to in time")
+                .build(),
+            rate_limit_rejections: meter
+                .u64_counter("defguard_proxy.rate_limit.rejections")
+                .with_description("HTTP requests rejected by the rate limiter")
+                .build(),
+            lockouts: meter
+                .u64_counter("defguard_proxy.lockouts")
+                .with_description(
+                    "Client addresses and tokens locked out after failed authentication attempts",
+                )
+                .build(),
+            remote_mfa_sessions: meter
+                .i64_up_down_counter("defguard_proxy.remote_mfa.sessions")
+                .with_description("Active remote MFA websocket sessions")
+                .build(),
+        }
+    }
+}
+
+/// Global proxy metrics.
+pub(crate) fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Name of Defguard Core request, used as a metric attribute.
+// The proto definitions live in a submodule, which may define requests unknown here.
+#[allow(unreachable_patterns)]
+pub(crate) fn core_request_name(payload: &core_request::Payload) -> &'static str {
+    match payload {
+        core_request::Payload::EnrollmentStart(_) => "enrollment_start",
+        core_request::Payload::ActivateUser(_) => "activate_user",
+        core_request::Payload::NewDevice(_) => "new_device",
+        core_request::Payload::ExistingDevice(_) => "existing_device",
+        core_request::Payload::PasswordResetInit(_) => "password_reset_init",
+        core_request::Payload::PasswordResetStart(_) => "password_reset_start",
+        core_request::Payload::PasswordReset(_) => "password_reset",
+        core_request::Payload::ClientMfaStart(_) => "client_mfa_start",
+        core_request::Payload::ClientMfaFinish(_) => "client_mfa_finish",
+        core_request::Payload::InstanceInfo(_) => "instance_info",
+        core_request::Payload::AuthInfo(_) => "auth_info",
+        core_request::Payload::AuthCallback(_) => "auth_callback",
+        core_request::Payload::CodeMfaSetupStart(_) => "code_mfa_setup_start",
+        core_request::Payload::CodeMfaSetupFinish(_) => "code_mfa_setup_finish",
+        core_request::Payload::ClientMfaOidcAuthenticate(_) => "client_mfa_oidc_authenticate",
+        core_request::Payload::RegisterMobileAuth(_) => "register_mobile_auth",
+        core_request::Payload::ClientMfaTokenValidation(_) => "client_mfa_token_validation",
+        _ => "other",
+    }
+}
+
+/// Record duration of HTTP requests by method, route and response status.
+pub(crate) async fn http_metrics_middleware(request: Request<Body>, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+    let response = next.run(request).await;
+    metrics().http_request_duration.record(
+        start.elapsed().as_secs_f64(),
+        &[
+            KeyValue::new("http.request.method", method),
+            KeyValue::new("http.route", route),
+            KeyValue::new(
+                "http.response.status_code",
+                i64::from(response.status().as_u16()),
+            ),
+        ],
+    );
+    response
+}
+
+/// Metric reader collecting metrics on demand, to render them in Prometheus text format.
+#[derive(Clone, Debug, Default)]
+pub struct PrometheusReader(Arc<ManualReader>);
+
+impl MetricReader for PrometheusReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.0.register_pipeline(pipeline);
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> OTelSdkResult {
+        self.0.collect(rm)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.0.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.0.temporality(kind)
+    }
+}
+
+impl PrometheusReader {
+    /// Collect current metrics in Prometheus text exposition format.
+    fn render(&self) -> Result<String, fmt::Error> {
+        let mut metrics = ResourceMetrics::default();
+        if let Err(err) = self.collect(&mut metrics) {
+            error!("Failed to collect metrics: {err}");
+            return Err(fmt::Error);
+        }
+        let mut output = String::new();
+        for scope in metrics.scope_metrics() {
+            for metric in scope.metrics() {
+                match metric.data() {
+                    AggregatedMetrics::F64(data) => write_metric(&mut output, metric, data)?,
+                    AggregatedMetrics::U64(data) => write_metric(&mut output, metric, data)?,
+                    AggregatedMetrics::I64(data) => write_metric(&mut output, metric, data)?,
+                }
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// Serve metrics in Prometheus format.
+pub(crate) async fn prometheus_metrics(State(reader): State<PrometheusReader>) -> Response {
+    match reader.render() {
+        Ok(body) => ([(CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)], body).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+// Replace characters not allowed in Prometheus metric and label names.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+// Prometheus metric name: dots replaced, unit and counter suffixes appended.
+fn prometheus_name(metric: &Metric, counter: bool) -> String {
+    let mut name = sanitize(metric.name());
+    if metric.unit() == "s" {
+        name.push_str("_seconds");
+    }
+    if counter {
+        name.push_str("_total");
+    }
+    name
+}
+
+// Labels sorted by name, `extra` label (histogram bucket) goes last.
+fn write_labels<'a>(
+    output: &mut String,
+    attributes: impl Iterator<Item = &'a KeyValue>,
+    extra: Option<(&str, &str)>,
+) -> fmt::Result {
+    let mut labels: Vec<_> = attributes
+        .map(|kv| (sanitize(kv.key.as_str()), kv.value.as_str().into_owned()))
+        .collect();
+    labels.sort();
+    labels.extend(extra.map(|(name, value)| (name.to_string(), value.to_string())));
+    if labels.is_empty() {
+        return Ok(());
+    }
+    output.push('{');
+    for (i, (name, value)) in labels.into_iter().enumerate() {
+        if i > 0 {
+            output.push(',');
+        }
+        let value = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        write!(output, "{name}=\"{value}\"")?;
+    }
+    output.push('}');
+    Ok(())
+}
+
+fn write_metric<T: Copy + fmt::Display>(
+    output: &mut String,
+    metric: &Metric,
+    data: &MetricData<T>,
+) -> fmt::Result {
+    let (kind, counter) = match data {
+        MetricData::Gauge(_) => ("gauge", false),
+        MetricData::Sum(sum) if sum.is_monotonic() => ("counter", true),
+        MetricData::Sum(_) => ("gauge", false),
+        MetricData::Histogram(_) => ("histogram", false),
+        // not used by the proxy
+        MetricData::ExponentialHistogram(_) => return Ok(()),
+    };
+    let name = prometheus_name(metric, counter);
+    writeln!(output, "# HELP {name} {}", metric.description())?;
+    writeln!(output, "# TYPE {name} {kind}")?;
+    match data {
+        MetricData::Gauge(gauge) => {
+            for point in gauge.data_points() {
+                output.push_str(&name);
+                write_labels(output, point.attributes(), None)?;
+                writeln!(output, " {}", point.value())?;
+            }
+        }
+        MetricData::Sum(sum) => {
+            for point in sum.data_points() {
+                output.push_str(&name);
+                write_labels(output, point.attributes(), None)?;
+                writeln!(output, " {}", point.value())?;
+            }
+        }
+        MetricData::Histogram(histogram) => {
+            for point in histogram.data_points() {
+                let mut cumulative = 0;
+                let bounds = point.bounds().map(|bound| bound.to_string());
+                for (bound, count) in bounds
+                    .chain(Some("+Inf".to_string()))
+                    .zip(point.bucket_counts())
+                {
+                    cumulative += count;
+                    write!(output, "{name}_bucket")?;
+                    write_labels(output, point.attributes(), Some(("le", &bound)))?;
+                    writeln!(output, " {cumulative}")?;
+                }
+                write!(output, "{name}_sum")?;
+                write_labels(output, point.attributes(), None)?;
+                writeln!(output, " {}", point.sum())?;
+                write!(output, "{name}_count")?;
+                write_labels(output, point.attributes(), None)?;
+                writeln!(output, " {}", point.count())?;
+            }
+        }
+        MetricData::ExponentialHistogram(_) => (),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+
+    use super::*;
+
+    #[test]
+    fn test_prometheus_format() {
+        let reader = PrometheusReader::default();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(reader.clone())
+            .build();
+        let meter = provider.meter("test");
+        let counter = meter
+            .u64_counter("defguard_proxy.core.timeouts")
+            .with_description("Timeouts")
+            .build();
+        counter.add(2, &[]);
+        let histogram = meter
+            .f64_histogram("http.server.request.duration")
+            .with_description("Duration")
+            .with_unit("s")
+            .with_boundaries(vec![0.1, 1.0])
+            .build();
+        histogram.record(0.05, &[KeyValue::new("http.route", "/api/v1/info")]);
+        histogram.record(0.5, &[KeyValue::new("http.route", "/api/v1/info")]);
+
+        let output = reader.render().unwrap();
+        assert!(output.contains("# TYPE defguard_proxy_core_timeouts_total counter\n"));
+        assert!(output.contains("defguard_proxy_core_timeouts_total 2\n"));
+        assert!(output.contains("# TYPE http_server_request_duration_seconds histogram\n"));
+        assert!(output.contains(
+            "http_server_request_duration_seconds_bucket{http_route=\"/api/v1/info\",le=\"0.1\"} 1\n"
+        ));
+        assert!(output.contains(
+            "http_server_request_duration_seconds_bucket{http_route=\"/api/v1/info\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(output.contains(
+            "http_server_request_duration_seconds_count{http_route=\"/api/v1/info\"} 2\n"
+        ));
+    }
+}
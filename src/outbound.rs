@@ -0,0 +1,518 @@
+use std::{
+    cmp::min,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use defguard_version::{
+    client::ClientVersionInterceptor, get_tracing_variables, ComponentInfo, DefguardComponent,
+    Version,
+};
+use hyper_util::rt::TokioIo;
+use rustls::{pki_types::ServerName, ClientConfig};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+use tokio_rustls::TlsConnector;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tonic::{
+    client::Grpc,
+    codec::CompressionEncoding,
+    service::interceptor::InterceptedService,
+    transport::{Endpoint, Uri},
+    Request,
+};
+use tonic_prost::ProstCodec;
+use tower::service_fn;
+use url::Url;
+
+use crate::{
+    grpc::ProxyServer,
+    proto::{CoreRequest, CoreResponse},
+    MIN_CORE_VERSION, VERSION,
+};
+
+/// RPC served by Defguard Core for proxies connecting to it. It mirrors `Proxy.Bidi` served
+/// by the proxy: the proxy streams `CoreRequest` messages and Core answers with `CoreResponse`.
+// TODO: use the generated client once the `CoreProxy` service is added to the proto definitions,
+// until then outbound mode is rejected by `config_errors`.
+pub(crate) const CORE_BIDI_PATH: &str = "/defguard.proxy.CoreProxy/Bidi";
+
+// Delay before the first reconnection attempt, doubled after each failure.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+// Maximum delay between reconnection attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// Connections which lasted at least that long reset the backoff.
+const BACKOFF_RESET_AFTER: Duration = Duration::from_secs(60);
+// Maximum time to connect, complete TLS handshake and open the gRPC stream.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// Exponential backoff between reconnection attempts.
+struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self {
+            delay: INITIAL_BACKOFF,
+        }
+    }
+
+    // Returns delay before the next attempt, the one after it is twice as long.
+    fn next(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = min(self.delay * 2, MAX_BACKOFF);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.delay = INITIAL_BACKOFF;
+    }
+}
+
+/// gRPC client of Defguard Core, used in outbound mode instead of Core connecting to the proxy.
+/// Requests are exchanged over the stream opened by the proxy, just like over streams opened
+/// by Core connecting to the gRPC server.
+pub(crate) struct CoreConnector {
+    url: Url,
+    tls: Option<(TlsConnector, ServerName<'static>)>,
+    endpoint: Endpoint,
+    server: ProxyServer,
+    // sends proxy version to Core
+    version_interceptor: ClientVersionInterceptor,
+    send_compression: Option<CompressionEncoding>,
+    accept_compression: Vec<CompressionEncoding>,
+    max_encoding_message_size: usize,
+    max_decoding_message_size: usize,
+}
+
+impl CoreConnector {
+    /// Create client of Defguard Core at `url`, using TLS for `https` URLs.
+    /// Connected Core is served requests sent through `server`.
+    pub(crate) fn new(
+        url: Url,
+        tls_config: Option<ClientConfig>,
+        server: ProxyServer,
+    ) -> anyhow::Result<Self> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing host in Defguard Core URL {url}"))?;
+        let tls = match (url.scheme(), tls_config) {
+            ("https", Some(config)) => {
+                // IPv6 address hosts are enclosed in brackets
+                let name = host.trim_start_matches('[').trim_end_matches(']');
+                Some((
+                    TlsConnector::from(Arc::new(config)),
+                    ServerName::try_from(name.to_string())?,
+                ))
+            }
+            ("https", None) => anyhow::bail!("TLS configuration required for {url}"),
+            ("http", _) => None,
+            (scheme, _) => anyhow::bail!("Unsupported Defguard Core URL scheme: {scheme}"),
+        };
+        let origin: Uri = url.as_str().parse()?;
+        // Connections are established by `connect`, so the channel doesn't need to handle TLS.
+        let endpoint = Endpoint::from_shared(format!("http://{}", url.authority()))?
+            .origin(origin)
+            .connect_timeout(CONNECT_TIMEOUT);
+        Ok(Self {
+            url,
+            tls,
+            endpoint,
+            server,
+            version_interceptor: ClientVersionInterceptor::new(Version::parse(VERSION)?),
+            send_compression: None,
+            accept_compression: Vec::new(),
+            max_encoding_message_size: usize::MAX,
+            max_decoding_message_size: usize::MAX,
+        })
+    }
+
+    /// Send HTTP/2 keepalive pings every `interval`, closing the connection if they're not
+    /// acknowledged within `timeout`.
+    #[must_use]
+    pub(crate) fn with_keepalive(mut self, interval: Option<Duration>, timeout: Duration) -> Self {
+        if let Some(interval) = interval {
+            self.endpoint = self
+                .endpoint
+                .clone()
+                .http2_keep_alive_interval(interval)
+                .keep_alive_timeout(timeout)
+                .keep_alive_while_idle(true);
+        }
+        self
+    }
+
+    /// Compress sent messages with `send`, and accept messages compressed with `accept`.
+    #[must_use]
+    pub(crate) fn with_compression(
+        mut self,
+        send: Option<CompressionEncoding>,
+        accept: Vec<CompressionEncoding>,
+    ) -> Self {
+        self.send_compression = send;
+        self.accept_compression = accept;
+        self
+    }
+
+    /// Limit size of sent and received messages.
+    #[must_use]
+    pub(crate) fn with_max_message_size(mut self, encoding: usize, decoding: usize) -> Self {
+        self.max_encoding_message_size = encoding;
+        self.max_decoding_message_size = decoding;
+        self
+    }
+
+    // Opens TCP connection to Defguard Core, with TLS for `https` URLs.
+    async fn dial(&self) -> io::Result<(Box<dyn Io>, SocketAddr)> {
+        let addrs = self.url.socket_addrs(|| None)?;
+        let stream = TcpStream::connect(&*addrs).await?;
+        let remote_addr = stream.peer_addr()?;
+        stream.set_nodelay(true)?;
+        let io: Box<dyn Io> = match &self.tls {
+            Some((connector, name)) => Box::new(connector.connect(name.clone(), stream).await?),
+            None => Box::new(stream),
+        };
+        Ok((io, remote_addr))
+    }
+
+    // Connects to Defguard Core and opens the gRPC stream. Returns the task reading Core
+    // responses, which ends when the stream does.
+    async fn connect(&self) -> anyhow::Result<JoinHandle<()>> {
+        let (io, address) = self.dial().await?;
+        // The channel only uses the established connection. It's not reconnected on failure,
+        // `run` opens a new one instead.
+        let io = Arc::new(Mutex::new(Some(io)));
+        let channel = self
+            .endpoint
+            .connect_with_connector(service_fn(move |_: Uri| {
+                let io = io.lock().unwrap().take();
+                async move {
+                    io.map(TokioIo::new).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::NotConnected,
+                            "Connection to Defguard Core closed",
+                        )
+                    })
+                }
+            }))
+            .await?;
+
+        let channel = InterceptedService::new(channel, self.version_interceptor.clone());
+        let mut grpc = Grpc::new(channel)
+            .max_encoding_message_size(self.max_encoding_message_size)
+            .max_decoding_message_size(self.max_decoding_message_size);
+        if let Some(encoding) = self.send_compression {
+            grpc = grpc.send_compressed(encoding);
+        }
+        for encoding in &self.accept_compression {
+            grpc = grpc.accept_compressed(*encoding);
+        }
+        grpc.ready().await?;
+        let (tx, rx) = self.server.request_channel();
+        let requests = ReceiverStream::new(rx).filter_map(Result::ok);
+        let response = grpc
+            .streaming(
+                Request::new(requests),
+                CORE_BIDI_PATH.parse()?,
+                ProstCodec::<CoreRequest, CoreResponse>::default(),
+            )
+            .await?;
+
+        // Like `Proxy.Bidi`, Core which doesn't report its version is rejected.
+        let maybe_info = ComponentInfo::from_metadata(response.metadata());
+        match &maybe_info {
+            Some(info) if info.version >= MIN_CORE_VERSION => (),
+            Some(info) => anyhow::bail!(
+                "Defguard Core version {} is not supported, minimum required version is \
+                {MIN_CORE_VERSION}",
+                info.version
+            ),
+            None => anyhow::bail!(
+                "Defguard Core didn't report its version, minimum required version is \
+                {MIN_CORE_VERSION}"
+            ),
+        }
+        let (version, info) = get_tracing_variables(&maybe_info);
+        let span = tracing::info_span!("core_bidi_stream", component = %DefguardComponent::Core,
+            version = version.to_string(), info);
+        let _guard = span.enter();
+        info!("Connected to Defguard Core at {} ({address})", self.url);
+        Ok(self
+            .server
+            .add_core_stream(address, version, tx, response.into_inner()))
+    }
+
+    /// Keep a connection to Defguard Core open, reconnecting with exponential backoff.
+    pub(crate) async fn run(self) {
+        let mut backoff = Backoff::new();
+        loop {
+            match timeout(CONNECT_TIMEOUT, self.connect()).await {
+                Ok(Ok(stream)) => {
+                    let connected_at = Instant::now();
+                    let _ = stream.await;
+                    warn!("Connection to Defguard Core at {} closed", self.url);
+                    if connected_at.elapsed() >= BACKOFF_RESET_AFTER {
+                        backoff.reset();
+                    }
+                }
+                Ok(Err(err)) => {
+                    warn!("Failed to connect to Defguard Core at {}: {err}", self.url);
+                }
+                Err(_) => warn!("Connecting to Defguard Core at {} timed out", self.url),
+            }
+            let delay = backoff.next();
+            debug!("Reconnecting to Defguard Core in {delay:?}");
+            sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        num::NonZeroUsize,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use axum::{serve, Router};
+    use defguard_version::server::DefguardVersionLayer;
+    use tokio::net::TcpListener;
+    use tonic::{server::Grpc as GrpcServer, Response, Status, Streaming};
+    use tower::Layer;
+
+    use super::*;
+    use crate::{
+        config::CoreSelection,
+        error::ApiError,
+        proto::{
+            core_request, core_response, DeviceInfo, InstanceInfoRequest, InstanceInfoResponse,
+        },
+    };
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new();
+        let delays: Vec<_> = (0..8).map(|_| backoff.next().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+        backoff.reset();
+        assert_eq!(backoff.next(), INITIAL_BACKOFF);
+    }
+
+    // Defguard Core answering the first request of each stream, then closing it.
+    // Instance info requests are answered with the token as instance name.
+    #[derive(Clone, Default)]
+    struct FakeCore {
+        // version reported to the proxy, none if not set
+        version: Option<Version>,
+        connections: Arc<AtomicUsize>,
+        // `grpc-encoding` of the streams opened by the proxy
+        encodings: Arc<Mutex<Vec<Option<String>>>>,
+        // versions reported by the proxy
+        proxy_versions: Arc<Mutex<Vec<Option<Version>>>>,
+    }
+
+    impl tonic::server::StreamingService<CoreRequest> for FakeCore {
+        type Response = CoreResponse;
+        type ResponseStream = ReceiverStream<Result<CoreResponse, Status>>;
+        type Future = std::future::Ready<Result<Response<Self::ResponseStream>, Status>>;
+
+        fn call(&mut self, request: Request<Streaming<CoreRequest>>) -> Self::Future {
+            self.connections.fetch_add(1, Ordering::Relaxed);
+            let proxy_version =
+                ComponentInfo::from_metadata(request.metadata()).map(|info| info.version);
+            self.proxy_versions.lock().unwrap().push(proxy_version);
+            let mut requests = request.into_inner();
+            let (tx, rx) = tokio::sync::mpsc::channel(1);
+            tokio::spawn(async move {
+                if let Ok(Some(request)) = requests.message().await {
+                    let payload = match request.payload {
+                        Some(core_request::Payload::InstanceInfo(info)) => {
+                            core_response::Payload::InstanceInfo(InstanceInfoResponse {
+                                name: info.token,
+                            })
+                        }
+                        _ => core_response::Payload::Empty(()),
+                    };
+                    let response = CoreResponse {
+                        id: request.id,
+                        payload: Some(payload),
+                    };
+                    let _ = tx.send(Ok(response)).await;
+                }
+            });
+            std::future::ready(Ok(Response::new(ReceiverStream::new(rx))))
+        }
+    }
+
+    impl FakeCore {
+        // Core reporting the minimum supported version.
+        fn new() -> Self {
+            Self {
+                version: Some(MIN_CORE_VERSION),
+                ..Default::default()
+            }
+        }
+
+        // Serves Core gRPC service, returns its URL.
+        async fn start(&self) -> Url {
+            let core = self.clone();
+            let service = service_fn(move |request: axum::extract::Request| {
+                let core = core.clone();
+                async move {
+                    let encoding = request
+                        .headers()
+                        .get("grpc-encoding")
+                        .map(|value| value.to_str().unwrap().to_string());
+                    core.encodings.lock().unwrap().push(encoding);
+                    let mut grpc =
+                        GrpcServer::new(ProstCodec::<CoreResponse, CoreRequest>::default())
+                            .accept_compressed(CompressionEncoding::Zstd)
+                            .send_compressed(CompressionEncoding::Zstd);
+                    Ok::<_, Infallible>(grpc.streaming(core, request).await)
+                }
+            });
+            let app = match &self.version {
+                Some(version) => Router::new().route_service(
+                    CORE_BIDI_PATH,
+                    DefguardVersionLayer::new(version.clone()).layer(service),
+                ),
+                None => Router::new().route_service(CORE_BIDI_PATH, service),
+            };
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move { serve(listener, app).await });
+            url.parse().unwrap()
+        }
+    }
+
+    fn new_server() -> ProxyServer {
+        ProxyServer::new(CoreSelection::Primary, NonZeroUsize::new(8).unwrap())
+            .with_reconnect_grace(Duration::from_secs(5), 1)
+    }
+
+    async fn instance_info(
+        server: &ProxyServer,
+        token: String,
+    ) -> Result<core_response::Payload, ApiError> {
+        let rx = server
+            .send(
+                core_request::Payload::InstanceInfo(InstanceInfoRequest { token }),
+                DeviceInfo::default(),
+            )
+            .await?;
+        rx.await
+            .map_err(|_| ApiError::Unexpected("Response channel closed".into()))
+    }
+
+    #[tokio::test]
+    async fn test_outbound_connection() {
+        let core = FakeCore::new();
+        let url = core.start().await;
+        let server = new_server();
+        let connector = tokio::spawn(CoreConnector::new(url, None, server.clone()).unwrap().run());
+
+        // request is held until the proxy connects, and answered by Core
+        let response = instance_info(&server, "first".into()).await.unwrap();
+        assert!(
+            matches!(response, core_response::Payload::InstanceInfo(info) if info.name == "first")
+        );
+        // Core closed the stream, the proxy reconnects
+        let response = instance_info(&server, "second".into()).await.unwrap();
+        assert!(
+            matches!(response, core_response::Payload::InstanceInfo(info) if info.name == "second")
+        );
+        assert_eq!(core.connections.load(Ordering::Relaxed), 2);
+        // messages are not compressed by default
+        assert_eq!(*core.encodings.lock().unwrap(), [None, None]);
+        // proxy reports its version
+        let version = Version::parse(VERSION).unwrap();
+        assert_eq!(
+            *core.proxy_versions.lock().unwrap(),
+            [Some(version.clone()), Some(version)]
+        );
+
+        connector.abort();
+    }
+
+    #[tokio::test]
+    async fn test_outbound_core_version() {
+        // Core not reporting its version is rejected
+        let core = FakeCore::default();
+        let connector = CoreConnector::new(core.start().await, None, new_server()).unwrap();
+        let err = connector.connect().await.unwrap_err();
+        assert!(err.to_string().contains("didn't report its version"));
+
+        // so is Core older than the minimum supported version
+        let core = FakeCore {
+            version: Some(Version::new(1, 5, 0)),
+            ..Default::default()
+        };
+        let connector = CoreConnector::new(core.start().await, None, new_server()).unwrap();
+        let err = connector.connect().await.unwrap_err();
+        assert!(err.to_string().contains("1.5.0 is not supported"));
+    }
+
+    #[tokio::test]
+    async fn test_outbound_compression() {
+        let core = FakeCore::new();
+        let url = core.start().await;
+        let server = new_server();
+        let connector = CoreConnector::new(url, None, server.clone())
+            .unwrap()
+            .with_compression(
+                Some(CompressionEncoding::Zstd),
+                vec![CompressionEncoding::Zstd],
+            );
+        let connector = tokio::spawn(connector.run());
+
+        // compressed request and response
+        let token = "a".repeat(10_000);
+        let response = instance_info(&server, token.clone()).await.unwrap();
+        assert!(
+            matches!(response, core_response::Payload::InstanceInfo(info) if info.name == token)
+        );
+        assert_eq!(*core.encodings.lock().unwrap(), [Some("zstd".into())]);
+
+        connector.abort();
+    }
+
+    #[tokio::test]
+    async fn test_outbound_max_message_size() {
+        let core = FakeCore::new();
+        let url = core.start().await;
+        let server = new_server().with_max_message_size(2000);
+        let connector = CoreConnector::new(url, None, server.clone())
+            .unwrap()
+            .with_max_message_size(2000, 1000);
+        let connector = tokio::spawn(connector.run());
+
+        // request too large to send is rejected right away
+        let result = instance_info(&server, "a".repeat(3000)).await;
+        assert!(
+            matches!(result, Err(ApiError::Unexpected(message)) if message == "Request too large")
+        );
+        assert_eq!(core.connections.load(Ordering::Relaxed), 0);
+        // response too large to receive closes the stream and fails the request
+        assert!(instance_info(&server, "a".repeat(1500)).await.is_err());
+        assert_eq!(core.connections.load(Ordering::Relaxed), 1);
+        // smaller ones are exchanged over a new connection
+        let response = instance_info(&server, "small".into()).await.unwrap();
+        assert!(
+            matches!(response, core_response::Payload::InstanceInfo(info) if info.name == "small")
+        );
+
+        connector.abort();
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use opentelemetry::KeyValue;
+use tokio::sync::oneshot;
+
+use crate::{metrics::metrics, proto::core_response::Payload};
+
+// How often expired requests are evicted.
+const CLEANUP_PERIOD: Duration = Duration::from_secs(5);
+
+struct PendingRequest {
+    tx: oneshot::Sender<Payload>,
+    // request name, for metrics
+    name: &'static str,
+    sent_at: Instant,
+    expires_at: Instant,
+}
+
+/// Requests sent to Defguard Core which await a response. Requests are keyed by core session
+/// (a single `Bidi` stream) and request ID, so a response can only complete a request sent
+/// over the same stream.
+pub(crate) struct PendingRequests {
+    requests: Mutex<HashMap<(u64, u64), PendingRequest>>,
+    ttl: Duration,
+}
+
+impl PendingRequests {
+    /// Create new registry, in which requests expire after `ttl`.
+    #[must_use]
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            requests: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Register request `id` named `name` sent over `session`. Returns receiver of the response,
+    /// which fails if the request expires or the session ends before a response comes.
+    pub(crate) fn register(
+        &self,
+        session: u64,
+        id: u64,
+        name: &'static str,
+    ) -> oneshot::Receiver<Payload> {
+        let (tx, rx) = oneshot::channel();
+        let now = Instant::now();
+        let request = PendingRequest {
+            tx,
+            name,
+            sent_at: now,
+            expires_at: now + self.ttl,
+        };
+        self.requests.lock().unwrap().insert((session, id), request);
+        rx
+    }
+
+    /// Forget request which couldn't be sent.
+    pub(crate) fn remove(&self, session: u64, id: u64) {
+        self.requests.lock().unwrap().remove(&(session, id));
+    }
+
+    /// Pass response to the waiter. Returns `false` if there was no such pending request,
+    /// or the waiter is gone.
+    pub(crate) fn complete(&self, session: u64, id: u64, payload: Payload) -> bool {
+        let Some(request) = self.requests.lock().unwrap().remove(&(session, id)) else {
+            return false;
+        };
+        metrics().core_request_duration.record(
+            request.sent_at.elapsed().as_secs_f64(),
+            &[KeyValue::new("request", request.name)],
+        );
+        request.tx.send(payload).is_ok()
+    }
+
+    /// Number of requests awaiting a response.
+    pub(crate) fn len(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+
+    /// Fail all requests sent over `session`, waiters are notified right away.
+    /// Returns number of failed requests.
+    pub(crate) fn fail_session(&self, session: u64) -> usize {
+        let mut requests = self.requests.lock().unwrap();
+        let count = requests.len();
+        requests.retain(|(request_session, _), _| *request_session != session);
+        count - requests.len()
+    }
+
+    /// Evict expired requests and the ones nobody waits for anymore.
+    /// Returns number of evicted requests.
+    pub(crate) fn evict_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        let count = requests.len();
+        requests.retain(|_, request| request.expires_at > now && !request.tx.is_closed());
+        count - requests.len()
+    }
+
+    /// Periodically evict expired requests.
+    pub(crate) async fn run_cleanup(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(CLEANUP_PERIOD).await;
+            let evicted = self.evict_expired();
+            if evicted > 0 {
+                debug!("Evicted {evicted} expired Defguard Core requests");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot::error::TryRecvError;
+
+    use super::*;
+    use crate::proto::CoreError;
+
+    fn payload() -> Payload {
+        Payload::CoreError(CoreError::default())
+    }
+
+    #[test]
+    fn test_responses_scoped_to_session() {
+        let pending = PendingRequests::new(Duration::from_secs(5));
+        let mut rx = pending.register(1, 10, "test");
+        // same request ID, but different session
+        assert!(!pending.complete(2, 10, payload()));
+        assert!(rx.try_recv().is_err());
+        assert!(pending.complete(1, 10, payload()));
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_fail_session() {
+        let pending = PendingRequests::new(Duration::from_secs(5));
+        let mut first = pending.register(1, 10, "test");
+        let mut second = pending.register(2, 11, "test");
+        assert_eq!(pending.fail_session(1), 1);
+        assert!(matches!(first.try_recv(), Err(TryRecvError::Closed)));
+        assert!(matches!(second.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[test]
+    fn test_evict_expired() {
+        let pending = PendingRequests::new(Duration::ZERO);
+        let _rx = pending.register(1, 10, "test");
+        let kept = PendingRequests::new(Duration::from_secs(5));
+        let _rx = kept.register(1, 10, "test");
+        // receiver dropped
+        drop(kept.register(1, 11, "test"));
+        assert_eq!(pending.evict_expired(), 1);
+        assert_eq!(kept.evict_expired(), 1);
+    }
+}
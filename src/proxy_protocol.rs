@@ -0,0 +1,406 @@
+use std::{
+    future::pending,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::serve::Listener;
+use ipnet::IpNet;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::timeout,
+};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tonic::transport::server::{Connected, TcpConnectInfo};
+
+use crate::tls::handle_accept_error;
+
+// Maximum time a load balancer has to send the PROXY protocol header.
+pub(crate) const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+// Number of connections with received PROXY protocol header waiting to be picked up by the server.
+const PROXY_ACCEPT_BACKLOG: usize = 128;
+// Maximum length of version 1 header, including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+fn invalid_header(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid PROXY protocol header: {message}"),
+    )
+}
+
+/// Read PROXY protocol (version 1 or 2) header from `stream`, returns the client address.
+/// Returns `None` for connections not relayed on behalf of a client, like load balancer
+/// health checks, and for unsupported address families.
+pub(crate) async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> io::Result<Option<SocketAddr>> {
+    // both versions are at least as long as the version 2 signature
+    let mut start = [0; V2_SIGNATURE.len()];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(V1_PREFIX) {
+        let mut header = start.to_vec();
+        // read byte by byte, so nothing past the header is consumed
+        while !header.ends_with(b"\r\n") {
+            if header.len() == V1_MAX_LENGTH {
+                return Err(invalid_header("version 1 header too long"));
+            }
+            header.push(stream.read_u8().await?);
+        }
+        parse_v1(&header[V1_PREFIX.len()..header.len() - 2])
+    } else {
+        Err(invalid_header("missing signature"))
+    }
+}
+
+// Parses version 1 header without the prefix and CRLF, e.g. `TCP4 1.1.1.1 2.2.2.2 4711 443`.
+fn parse_v1(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let header = std::str::from_utf8(header).map_err(|_| invalid_header("not ASCII"))?;
+    let mut parts = header.split(' ');
+    match parts.next() {
+        Some("TCP4" | "TCP6") => (),
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid_header("unsupported protocol")),
+    }
+    let (Some(source), Some(_), Some(port), Some(_), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return Err(invalid_header("malformed version 1 header"));
+    };
+    let ip: IpAddr = source
+        .parse()
+        .map_err(|_| invalid_header("invalid source address"))?;
+    let port = port
+        .parse()
+        .map_err(|_| invalid_header("invalid source port"))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+// Reads version 2 header following the signature.
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let length = stream.read_u16().await?;
+    let mut addresses = vec![0; length.into()];
+    stream.read_exact(&mut addresses).await?;
+    if version_command >> 4 != 2 {
+        return Err(invalid_header("unsupported version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL, sent by the load balancer on its own behalf
+        0 => return Ok(None),
+        // PROXY
+        1 => (),
+        _ => return Err(invalid_header("unsupported command")),
+    }
+    // addresses are followed by optional TLVs, which are ignored
+    let source = match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap_or_default();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            SocketAddr::new(Ipv4Addr::from(ip).into(), port)
+        }
+        2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap_or_default();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            SocketAddr::new(Ipv6Addr::from(ip).into(), port)
+        }
+        1 | 2 => return Err(invalid_header("address block too short")),
+        // unspecified and UNIX socket addresses
+        _ => return Ok(None),
+    };
+    Ok(Some(source))
+}
+
+/// Peers allowed to relay connections with PROXY protocol, usually load balancers.
+#[derive(Clone, Debug)]
+pub(crate) struct TrustedPeers(Arc<Vec<IpNet>>);
+
+impl TrustedPeers {
+    #[must_use]
+    pub(crate) fn new(networks: Vec<IpNet>) -> Self {
+        Self(Arc::new(networks))
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Accepted TCP connection, with client address taken from PROXY protocol header if enabled.
+pub(crate) struct ProxiedStream {
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+}
+
+impl ProxiedStream {
+    /// Reads PROXY protocol header if `proxy_protocol` is enabled, connections from peers not
+    /// trusted to send it are rejected. Connections without client address in the header keep
+    /// the TCP peer address.
+    pub(crate) async fn accept(
+        mut stream: TcpStream,
+        proxy_protocol: Option<&TrustedPeers>,
+    ) -> io::Result<Self> {
+        let mut remote_addr = stream.peer_addr()?;
+        if let Some(trusted_peers) = proxy_protocol {
+            if !trusted_peers.contains(remote_addr.ip()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "peer is not in trusted_proxies",
+                ));
+            }
+            let source = timeout(PROXY_HEADER_TIMEOUT, read_header(&mut stream))
+                .await
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Timed out waiting for PROXY protocol header",
+                    )
+                })??;
+            if let Some(source) = source {
+                remote_addr = source;
+            }
+        }
+        Ok(Self {
+            stream,
+            remote_addr,
+        })
+    }
+
+    /// Client address.
+    #[must_use]
+    pub(crate) fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+}
+
+impl Connected for ProxiedStream {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        TcpConnectInfo {
+            local_addr: self.stream.local_addr().ok(),
+            remote_addr: Some(self.remote_addr),
+        }
+    }
+}
+
+impl AsyncRead for ProxiedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Accept connections on `listener` and read their PROXY protocol headers in the background,
+/// rejecting connections from peers other than `trusted_peers`.
+/// Returns a stream of connections, suitable for `serve_with_incoming`.
+pub(crate) fn proxied_incoming(
+    listener: TcpListener,
+    trusted_peers: TrustedPeers,
+) -> ReceiverStream<Result<ProxiedStream, io::Error>> {
+    let (tx, rx) = mpsc::channel(PROXY_ACCEPT_BACKLOG);
+    tokio::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    handle_accept_error(err).await;
+                    continue;
+                }
+            };
+            // server is gone, stop accepting connections
+            if tx.is_closed() {
+                break;
+            }
+            let tx = tx.clone();
+            let trusted_peers = trusted_peers.clone();
+            tokio::spawn(async move {
+                match ProxiedStream::accept(stream, Some(&trusted_peers)).await {
+                    Ok(stream) => {
+                        let _ = tx.send(Ok(stream)).await;
+                    }
+                    Err(err) => warn!("Failed to read PROXY protocol header from {addr}: {err}"),
+                }
+            });
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/// Listener accepting connections relayed with PROXY protocol, for use with `axum::serve`.
+pub(crate) struct ProxiedListener {
+    incoming: ReceiverStream<Result<ProxiedStream, io::Error>>,
+    local_addr: SocketAddr,
+}
+
+impl ProxiedListener {
+    pub(crate) fn new(listener: TcpListener, trusted_peers: TrustedPeers) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        Ok(Self {
+            incoming: proxied_incoming(listener, trusted_peers),
+            local_addr,
+        })
+    }
+}
+
+impl Listener for ProxiedListener {
+    type Io = ProxiedStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            match self.incoming.next().await {
+                Some(Ok(stream)) => {
+                    let addr = stream.remote_addr();
+                    return (stream, addr);
+                }
+                Some(Err(err)) => error!("Failed to accept connection: {err}"),
+                // accept loop never stops
+                None => pending().await,
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    async fn parse(mut header: &[u8]) -> io::Result<Option<SocketAddr>> {
+        let result = read_header(&mut header).await;
+        // data following the header is left in the stream
+        if result.is_ok() {
+            assert_eq!(header, b"GET");
+        }
+        result
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        // version 1
+        assert_eq!(
+            parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 4711 443\r\nGET")
+                .await
+                .unwrap(),
+            Some("192.0.2.1:4711".parse().unwrap())
+        );
+        assert_eq!(
+            parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\nGET")
+                .await
+                .unwrap(),
+            Some("[2001:db8::1]:4711".parse().unwrap())
+        );
+        assert_eq!(parse(b"PROXY UNKNOWN\r\nGET").await.unwrap(), None);
+        assert!(parse(b"PROXY TCP4 192.0.2.1\r\nGET").await.is_err());
+        assert!(parse(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        let mut long = b"PROXY UNKNOWN ".to_vec();
+        long.resize(200, b'x');
+        assert!(parse(&long).await.is_err());
+
+        // version 2
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 15]);
+        header.extend([192, 0, 2, 1, 198, 51, 100, 1, 0x12, 0x67, 0x01, 0xbb]);
+        // NOOP TLV
+        header.extend([0x04, 0, 0]);
+        header.extend(b"GET");
+        assert_eq!(
+            parse(&header).await.unwrap(),
+            Some("192.0.2.1:4711".parse().unwrap())
+        );
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x21, 0, 36]);
+        header.extend(Ipv6Addr::LOCALHOST.octets());
+        header.extend(Ipv6Addr::UNSPECIFIED.octets());
+        header.extend([0x12, 0x67, 0x01, 0xbb]);
+        header.extend(b"GET");
+        assert_eq!(
+            parse(&header).await.unwrap(),
+            Some("[::1]:4711".parse().unwrap())
+        );
+        // LOCAL command
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20, 0x00, 0, 0]);
+        header.extend(b"GET");
+        assert_eq!(parse(&header).await.unwrap(), None);
+        // truncated address block
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 4, 192, 0, 2, 1]);
+        header.extend(b"GET");
+        assert!(parse(&header).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_trusted_peers() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 4711 443\r\n";
+
+        // header from a trusted peer carries the client address
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let trusted_peers = TrustedPeers::new(vec!["127.0.0.0/8".parse().unwrap()]);
+        let mut listener = ProxiedListener::new(listener, trusted_peers).unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(header).await.unwrap();
+        let (_stream, remote_addr) = listener.accept().await;
+        assert_eq!(remote_addr, "192.0.2.1:4711".parse().unwrap());
+
+        // connections from other peers are closed without being served
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let trusted_peers = TrustedPeers::new(vec!["192.0.2.0/24".parse().unwrap()]);
+        let mut incoming = proxied_incoming(listener, trusted_peers);
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(header).await.unwrap();
+        let mut buf = [0; 1];
+        let read = timeout(Duration::from_secs(5), client.read(&mut buf))
+            .await
+            .unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert!(timeout(Duration::from_millis(100), incoming.next())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,480 @@
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use governor::middleware::NoOpMiddleware;
+use opentelemetry::KeyValue;
+use serde_json::{Map, Value};
+use tower::{Layer, ServiceExt};
+use tower_governor::{
+    governor::{GovernorConfig, GovernorConfigBuilder},
+    key_extractor::KeyExtractor,
+    GovernorError, GovernorLayer,
+};
+
+use crate::{
+    client_ip::{ClientIp, ClientIpKeyExtractor},
+    config::RateLimitPolicy,
+    metrics::metrics,
+};
+
+const RATE_LIMITER_CLEANUP_PERIOD: Duration = Duration::from_secs(60);
+// Maximum size of request body read to find fields a policy limits requests by.
+const POLICY_BODY_LIMIT: usize = 64 * 1024;
+// Name of the global rate limit in metrics.
+const DEFAULT_POLICY: &str = "default";
+
+pub(crate) type LimiterConfig = GovernorConfig<ClientIpKeyExtractor, NoOpMiddleware>;
+type PolicyLimiterConfig = GovernorConfig<PolicyKeyExtractor, NoOpMiddleware>;
+
+/// Identifies whose requests are counted together by a policy.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct PolicyKey {
+    ip: Option<IpAddr>,
+    fields: Vec<Option<String>>,
+}
+
+/// Key extractor using `PolicyKey` stored in request extensions by `rate_limit_middleware`.
+#[derive(Clone, Copy, Debug)]
+struct PolicyKeyExtractor;
+
+impl KeyExtractor for PolicyKeyExtractor {
+    type Key = PolicyKey;
+
+    fn extract<T>(&self, request: &axum::http::Request<T>) -> Result<Self::Key, GovernorError> {
+        request
+            .extensions()
+            .get::<PolicyKey>()
+            .cloned()
+            .ok_or(GovernorError::UnableToExtractKey)
+    }
+}
+
+struct PolicyLimiter {
+    name: String,
+    policy: RateLimitPolicy,
+    config: Option<Arc<PolicyLimiterConfig>>,
+}
+
+impl PolicyLimiter {
+    fn new(name: &str, policy: &RateLimitPolicy) -> Self {
+        debug!(
+            "Configuring rate limit policy {name}, per_second: {}, burst: {}",
+            policy.per_second, policy.burst
+        );
+        let config = GovernorConfigBuilder::default()
+            .key_extractor(PolicyKeyExtractor)
+            .per_second(policy.per_second)
+            .burst_size(policy.burst)
+            .finish();
+        if config.is_some() {
+            info!(
+                "Configured rate limit policy {name} for {}, per_second: {}, burst: {}",
+                policy.routes.join(", "),
+                policy.per_second,
+                policy.burst
+            );
+        } else {
+            info!(
+                "Rate limit policy {name} disables rate limiting of {}",
+                policy.routes.join(", ")
+            );
+        }
+        Self {
+            name: name.to_string(),
+            policy: policy.clone(),
+            config: config.map(Arc::new),
+        }
+    }
+
+    // Length of the longest route prefix matching `path`.
+    fn matched_length(&self, path: &str) -> Option<usize> {
+        self.policy
+            .routes
+            .iter()
+            .filter(|route| route_matches(route, path))
+            .map(|route| route.trim_end_matches('/').len())
+            .max()
+    }
+
+    fn is_exempt(&self, ip: Option<IpAddr>) -> bool {
+        ip.is_some_and(|ip| self.policy.exempt_cidrs.iter().any(|net| net.contains(&ip)))
+    }
+}
+
+struct RateLimiterState {
+    per_second: u64,
+    burst: u32,
+    config: Option<Arc<LimiterConfig>>,
+    policies: Vec<Arc<PolicyLimiter>>,
+}
+
+/// HTTP rate limiter whose settings can be replaced at runtime. Requests to routes of
+/// a rate limit policy are limited by the policy, all others by the global rate limit.
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    state: Arc<RwLock<RateLimiterState>>,
+}
+
+impl RateLimiter {
+    #[must_use]
+    pub(crate) fn new(
+        per_second: u64,
+        burst: u32,
+        policies: &BTreeMap<String, RateLimitPolicy>,
+    ) -> Self {
+        Self {
+            state: Arc::new(RwLock::new(RateLimiterState {
+                per_second,
+                burst,
+                config: Self::build_config(per_second, burst),
+                policies: policies
+                    .iter()
+                    .map(|(name, policy)| Arc::new(PolicyLimiter::new(name, policy)))
+                    .collect(),
+            })),
+        }
+    }
+
+    fn build_config(per_second: u64, burst: u32) -> Option<Arc<LimiterConfig>> {
+        debug!("Configuring rate limiter, per_second: {per_second}, burst: {burst}");
+        let config = GovernorConfigBuilder::default()
+            .key_extractor(ClientIpKeyExtractor)
+            .per_second(per_second)
+            .burst_size(burst)
+            .finish();
+        if config.is_some() {
+            info!("Configured rate limiter, per_second: {per_second}, burst: {burst}");
+        } else {
+            info!("Skipping rate limiter setup");
+        }
+        config.map(Arc::new)
+    }
+
+    /// Apply new rate limiter settings. Limiters whose settings haven't changed are kept,
+    /// so clients keep their current quota.
+    pub(crate) fn update(
+        &self,
+        per_second: u64,
+        burst: u32,
+        policies: &BTreeMap<String, RateLimitPolicy>,
+    ) {
+        let mut state = self.state.write().unwrap();
+        if state.per_second != per_second || state.burst != burst {
+            state.per_second = per_second;
+            state.burst = burst;
+            state.config = Self::build_config(per_second, burst);
+        }
+        state.policies = policies
+            .iter()
+            .map(|(name, policy)| {
+                state
+                    .policies
+                    .iter()
+                    .find(|limiter| &limiter.name == name && &limiter.policy == policy)
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(PolicyLimiter::new(name, policy)))
+            })
+            .collect();
+    }
+
+    /// Global rate limit configuration, `None` if it's disabled.
+    pub(crate) fn current(&self) -> Option<Arc<LimiterConfig>> {
+        self.state.read().unwrap().config.clone()
+    }
+
+    // Policy with the most specific route matching `path`.
+    fn policy(&self, path: &str) -> Option<Arc<PolicyLimiter>> {
+        self.state
+            .read()
+            .unwrap()
+            .policies
+            .iter()
+            .filter_map(|limiter| Some((limiter.matched_length(path)?, limiter)))
+            .max_by_key(|(length, _)| *length)
+            .map(|(_, limiter)| Arc::clone(limiter))
+    }
+
+    /// Periodically clean-up rate limiter storage.
+    pub(crate) async fn run_cleanup(self) {
+        loop {
+            tokio::time::sleep(RATE_LIMITER_CLEANUP_PERIOD).await;
+            let (config, policies) = {
+                let state = self.state.read().unwrap();
+                (state.config.clone(), state.policies.clone())
+            };
+            if let Some(config) = config {
+                let limiter = config.limiter();
+                debug!(
+                    "Cleaning-up rate limiter storage, current size: {}",
+                    limiter.len()
+                );
+                limiter.retain_recent();
+            }
+            for policy in policies {
+                if let Some(config) = &policy.config {
+                    config.limiter().retain_recent();
+                }
+            }
+        }
+    }
+}
+
+/// Checks if `path` is the `route` path prefix or one of its subpaths.
+pub(crate) fn route_matches(route: &str, path: &str) -> bool {
+    path.strip_prefix(route.trim_end_matches('/'))
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+// Rate-limit the request with `config`, counting rejections under `policy` name.
+async fn limit<K>(
+    config: Arc<GovernorConfig<K, NoOpMiddleware>>,
+    policy: String,
+    request: Request,
+    next: Next,
+) -> Response<Body>
+where
+    K: KeyExtractor + Send + Sync + 'static,
+    K::Key: Send + Sync + 'static,
+{
+    GovernorLayer::new(config)
+        .error_handler(move |err| {
+            if matches!(err, GovernorError::TooManyRequests { .. }) {
+                metrics()
+                    .rate_limit_rejections
+                    .add(1, &[KeyValue::new("policy", policy.clone())]);
+            }
+            err.into()
+        })
+        .layer(next)
+        .oneshot(request)
+        .await
+        .unwrap_or_else(|err: Infallible| match err {})
+}
+
+/// Reads top-level string `fields` of JSON request body, the body is put back for the handler.
+pub(crate) async fn body_fields(
+    request: Request,
+    fields: &[String],
+) -> Result<(Request, Vec<Option<String>>), Response<Body>> {
+    let (parts, body) = request.into_parts();
+    let bytes = to_bytes(body, POLICY_BODY_LIMIT)
+        .await
+        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE.into_response())?;
+    let object = serde_json::from_slice::<Map<String, Value>>(&bytes).unwrap_or_default();
+    let values = fields
+        .iter()
+        .map(|field| object.get(field).and_then(Value::as_str).map(String::from))
+        .collect();
+    Ok((Request::from_parts(parts, Body::from(bytes)), values))
+}
+
+/// Rate-limit requests using the currently configured limiters, if any.
+pub(crate) async fn rate_limit_middleware(
+    State(rate_limiter): State<RateLimiter>,
+    request: Request,
+    next: Next,
+) -> Response<Body> {
+    let Some(limiter) = rate_limiter.policy(request.uri().path()) else {
+        return match rate_limiter.current() {
+            Some(config) => limit(config, DEFAULT_POLICY.into(), request, next).await,
+            None => next.run(request).await,
+        };
+    };
+    let ip = request
+        .extensions()
+        .get::<ClientIp>()
+        .map(|client_ip| client_ip.0);
+    let Some(config) = limiter.config.clone().filter(|_| !limiter.is_exempt(ip)) else {
+        return next.run(request).await;
+    };
+    let (mut request, fields) = if limiter.policy.by_fields.is_empty() {
+        (request, Vec::new())
+    } else {
+        match body_fields(request, &limiter.policy.by_fields).await {
+            Ok(result) => result,
+            Err(response) => return response,
+        }
+    };
+    // Requests without any of the fields would share a single quota, so they're limited by
+    // client IP instead.
+    let by_ip = limiter.policy.by_ip || (!fields.is_empty() && fields.iter().all(Option::is_none));
+    request.extensions_mut().insert(PolicyKey {
+        ip: ip.filter(|_| by_ip),
+        fields,
+    });
+    limit(config, limiter.name.clone(), request, next).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::post, Router};
+
+    use super::*;
+
+    fn policy(routes: &[&str], by_ip: bool, by_fields: &[&str]) -> RateLimitPolicy {
+        RateLimitPolicy {
+            routes: routes.iter().map(ToString::to_string).collect(),
+            per_second: 60,
+            burst: 1,
+            by_ip,
+            by_fields: by_fields.iter().map(ToString::to_string).collect(),
+            exempt_cidrs: vec!["10.0.0.0/8".parse().unwrap()],
+        }
+    }
+
+    async fn status(app: &Router, path: &str, ip: [u8; 4], body: &str) -> StatusCode {
+        let request = Request::post(path)
+            .extension(ClientIp(ip.into()))
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_policies() {
+        let policies = BTreeMap::from([
+            (
+                "password-reset".to_string(),
+                policy(&["/api/v1/password-reset"], false, &["email"]),
+            ),
+            (
+                "poll".to_string(),
+                policy(
+                    &["/api/v1/poll", "/api/v1/password-reset/start/"],
+                    true,
+                    &[],
+                ),
+            ),
+        ]);
+        let rate_limiter = RateLimiter::new(0, 0, &policies);
+        let app = Router::new()
+            .route(
+                "/api/v1/password-reset/request",
+                post(|body: String| async { body }),
+            )
+            .route("/api/v1/password-reset/start", post(|| async {}))
+            .route("/api/v1/poll", post(|| async {}))
+            .route("/api/v1/info", post(|| async {}))
+            .layer(middleware::from_fn_with_state(
+                rate_limiter.clone(),
+                rate_limit_middleware,
+            ));
+
+        // limited by email regardless of client IP, body is passed to the handler
+        let path = "/api/v1/password-reset/request";
+        let request = Request::post(path)
+            .extension(ClientIp([1, 1, 1, 1].into()))
+            .body(Body::from(r#"{"email":"a@example.com"}"#))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, r#"{"email":"a@example.com"}"#);
+        let email = r#"{"email":"a@example.com"}"#;
+        assert_eq!(
+            status(&app, path, [2, 2, 2, 2], email).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        let email = r#"{"email":"b@example.com"}"#;
+        assert_eq!(
+            status(&app, path, [2, 2, 2, 2], email).await,
+            StatusCode::OK
+        );
+        // without the field limited by client IP
+        assert_eq!(status(&app, path, [3, 3, 3, 3], "{}").await, StatusCode::OK);
+        assert_eq!(
+            status(&app, path, [3, 3, 3, 3], "invalid").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(status(&app, path, [4, 4, 4, 4], "{}").await, StatusCode::OK);
+        // exempt networks
+        assert_eq!(
+            status(&app, path, [10, 0, 0, 1], email).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&app, path, [10, 0, 0, 1], email).await,
+            StatusCode::OK
+        );
+
+        // the most specific route wins, limited by client IP
+        let path = "/api/v1/password-reset/start";
+        assert_eq!(status(&app, path, [1, 1, 1, 1], "").await, StatusCode::OK);
+        assert_eq!(
+            status(&app, "/api/v1/poll", [1, 1, 1, 1], "").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(status(&app, path, [2, 2, 2, 2], "").await, StatusCode::OK);
+
+        // other routes use the global rate limit, which is disabled
+        for _ in 0..3 {
+            assert_eq!(
+                status(&app, "/api/v1/info", [1, 1, 1, 1], "").await,
+                StatusCode::OK
+            );
+        }
+
+        // unchanged policies keep their state on update
+        let mut policies = policies;
+        policies.get_mut("password-reset").unwrap().burst = 2;
+        rate_limiter.update(0, 0, &policies);
+        assert_eq!(
+            status(&app, "/api/v1/poll", [2, 2, 2, 2], "").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        let path = "/api/v1/password-reset/request";
+        assert_eq!(
+            status(&app, path, [1, 1, 1, 1], email).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_update() {
+        let rate_limiter = RateLimiter::new(60, 1, &BTreeMap::new());
+        let app = Router::new()
+            .route("/api/v1/info", post(|| async {}))
+            .layer(middleware::from_fn_with_state(
+                rate_limiter.clone(),
+                rate_limit_middleware,
+            ));
+        let path = "/api/v1/info";
+        assert_eq!(status(&app, path, [1, 1, 1, 1], "").await, StatusCode::OK);
+        assert_eq!(
+            status(&app, path, [1, 1, 1, 1], "").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        // unchanged settings keep the current quota
+        rate_limiter.update(60, 1, &BTreeMap::new());
+        assert_eq!(
+            status(&app, path, [1, 1, 1, 1], "").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // changed settings start over
+        rate_limiter.update(60, 2, &BTreeMap::new());
+        for _ in 0..2 {
+            assert_eq!(status(&app, path, [1, 1, 1, 1], "").await, StatusCode::OK);
+        }
+        assert_eq!(
+            status(&app, path, [1, 1, 1, 1], "").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // disabled
+        rate_limiter.update(0, 0, &BTreeMap::new());
+        assert!(rate_limiter.current().is_none());
+        assert_eq!(status(&app, path, [1, 1, 1, 1], "").await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,209 @@
+use std::sync::{Arc, RwLock};
+
+use serde_json::Value;
+use tokio::signal::unix::{signal, SignalKind};
+use url::Url;
+
+use crate::{
+    config::{get_config, Config},
+    lockout::Lockout,
+    logging::{log_filter, LogFilterHandle},
+    ratelimit::RateLimiter,
+    tls::CertResolver,
+};
+
+// Settings applied by `apply_config` without restarting the proxy, all others require a restart.
+const LIVE_SETTINGS: &[&str] = &[
+    "log_level",
+    "rate_limit_per_second",
+    "rate_limit_burst",
+    "rate_limit_policies",
+    "lockout_threshold",
+    "lockout_window",
+    "lockout_duration",
+    "grpc_cert",
+    "grpc_key",
+    "http_cert",
+    "http_key",
+    "url",
+];
+
+/// Handles to runtime state which can be updated without restarting the proxy.
+pub(crate) struct ReloadHandles {
+    pub(crate) log_filter: LogFilterHandle,
+    pub(crate) rate_limiter: RateLimiter,
+    pub(crate) lockout: Lockout,
+    pub(crate) grpc_cert_resolver: Option<Arc<CertResolver>>,
+    pub(crate) http_cert_resolver: Option<Arc<CertResolver>>,
+    pub(crate) url: Arc<RwLock<Url>>,
+}
+
+/// Reload configuration every time SIGHUP is received.
+pub(crate) async fn run_reload_handler(
+    mut config: Config,
+    handles: ReloadHandles,
+) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading configuration");
+        match get_config() {
+            Ok(new_config) => {
+                new_config.log_sources();
+                apply_config(&mut config, new_config, &handles);
+            }
+            Err(err) => error!("Failed to reload configuration, keeping the current one: {err}"),
+        }
+    }
+    Ok(())
+}
+
+/// Applies settings which can be changed live and warns about the ones which can't.
+fn apply_config(current: &mut Config, new: Config, handles: &ReloadHandles) {
+    for name in restart_required(current, &new) {
+        warn!("Changed {name}, restart required to apply it");
+    }
+
+    // log level
+    if let Err(err) = handles.log_filter.reload(log_filter(&new.log_level)) {
+        error!("Failed to reload log filter: {err}");
+    } else {
+        current.log_level = new.log_level;
+        info!("Log level set to {}", current.log_level);
+    }
+
+    // rate limiter
+    handles.rate_limiter.update(
+        new.rate_limit_per_second,
+        new.rate_limit_burst,
+        &new.rate_limit_policies,
+    );
+    current.rate_limit_per_second = new.rate_limit_per_second;
+    current.rate_limit_burst = new.rate_limit_burst;
+    current.rate_limit_policies = new.rate_limit_policies;
+
+    // brute-force lockout
+    handles.lockout.update(
+        new.lockout_threshold,
+        new.lockout_window,
+        new.lockout_duration,
+    );
+    current.lockout_threshold = new.lockout_threshold;
+    current.lockout_window = new.lockout_window;
+    current.lockout_duration = new.lockout_duration;
+
+    // TLS certificates
+    if reload_cert(
+        "gRPC",
+        handles.grpc_cert_resolver.as_deref(),
+        new.grpc_cert.as_deref().zip(new.grpc_key.as_deref()),
+    ) {
+        current.grpc_cert = new.grpc_cert;
+        current.grpc_key = new.grpc_key;
+    }
+    if reload_cert(
+        "HTTPS",
+        handles.http_cert_resolver.as_deref(),
+        new.http_cert.as_deref().zip(new.http_key.as_deref()),
+    ) {
+        current.http_cert = new.http_cert;
+        current.http_key = new.http_key;
+    }
+
+    // public URL
+    if current.url != new.url {
+        info!("Public URL changed to {}", new.url);
+        handles.url.write().unwrap().clone_from(&new.url);
+        current.url = new.url;
+    }
+}
+
+/// Names of changed settings which can't be applied live.
+fn restart_required(current: &Config, new: &Config) -> Vec<String> {
+    let (Ok(Value::Object(current)), Ok(Value::Object(new))) =
+        (serde_json::to_value(current), serde_json::to_value(new))
+    else {
+        return Vec::new();
+    };
+    current
+        .into_iter()
+        .filter(|(name, value)| {
+            !LIVE_SETTINGS.contains(&name.as_str()) && new.get(name) != Some(value)
+        })
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Reloads certificate served by `resolver`. Returns `true` if the certificate was reloaded.
+fn reload_cert(name: &str, resolver: Option<&CertResolver>, files: Option<(&str, &str)>) -> bool {
+    match (resolver, files) {
+        (Some(resolver), Some((cert, key))) => match resolver.reload_from_files(cert, key) {
+            Ok(()) => return true,
+            Err(err) => {
+                error!("Failed to reload {name} TLS certificate, keeping the current one: {err}");
+            }
+        },
+        (None, None) => (),
+        _ => warn!("Changed {name} TLS configuration, restart required to apply it"),
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, num::NonZeroUsize};
+
+    use log::LevelFilter;
+    use tracing_subscriber::{reload, EnvFilter};
+
+    use super::*;
+
+    #[test]
+    fn test_apply_config() {
+        let (_filter, log_filter) = reload::Layer::new(EnvFilter::new("info"));
+        let mut config = Config::parse_without_env(["defguard-proxy"]);
+        let handles = ReloadHandles {
+            log_filter,
+            rate_limiter: RateLimiter::new(0, 0, &BTreeMap::new()),
+            lockout: Lockout::new(0, 600, 900),
+            grpc_cert_resolver: None,
+            http_cert_resolver: None,
+            url: Arc::new(RwLock::new(config.url.clone())),
+        };
+
+        let mut new = Config::parse_without_env(["defguard-proxy"]);
+        new.http_port = 9090;
+        new.log_level = LevelFilter::Debug;
+        new.rate_limit_per_second = 10;
+        new.rate_limit_burst = 5;
+        new.lockout_threshold = 3;
+        new.http_cert = Some("cert.pem".into());
+        new.http_key = Some("key.pem".into());
+        new.url = Url::parse("https://proxy.example.com").unwrap();
+        new.cookie_key = Some("secret".into());
+        new.core_queue_size = NonZeroUsize::new(1).unwrap();
+        // every changed setting which isn't applied live requires restart
+        assert_eq!(
+            restart_required(&config, &new),
+            ["cookie_key", "core_queue_size", "http_port"]
+        );
+        apply_config(&mut config, new, &handles);
+
+        // live settings are applied
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert_eq!(
+            handles
+                .log_filter
+                .with_current(ToString::to_string)
+                .unwrap(),
+            "debug"
+        );
+        assert_eq!(config.rate_limit_burst, 5);
+        assert!(handles.rate_limiter.current().is_some());
+        assert_eq!(config.lockout_threshold, 3);
+        assert_eq!(config.url.as_str(), "https://proxy.example.com/");
+        assert_eq!(*handles.url.read().unwrap(), config.url);
+        // ones requiring restart are not
+        assert_eq!(config.http_port, 8080);
+        assert_eq!(config.http_cert, None);
+    }
+}
@@ -0,0 +1,191 @@
+use std::fmt::Write;
+
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::trace::{SpanContext, TraceContextExt};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+static TRACEPARENT_HEADER: HeaderName = HeaderName::from_static("traceparent");
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Identifies an HTTP request across proxy and Defguard Core logs. The request ID is taken from
+/// `x-request-id` header, W3C trace context from the request span exported with OpenTelemetry.
+/// Without export, trace context is taken from `traceparent` header. Both are generated if
+/// missing or invalid.
+#[derive(Clone, Debug)]
+pub(crate) struct RequestContext {
+    pub(crate) request_id: String,
+    trace_id: String,
+    span_id: String,
+    flags: String,
+}
+
+impl RequestContext {
+    fn new(headers: &HeaderMap, span_context: &SpanContext) -> Self {
+        let (trace_id, span_id, flags) = if span_context.is_valid() {
+            (
+                span_context.trace_id().to_string(),
+                span_context.span_id().to_string(),
+                format!("{:02x}", span_context.trace_flags().to_u8()),
+            )
+        } else {
+            let parent = headers
+                .get(&TRACEPARENT_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_traceparent);
+            let (trace_id, flags) = parent.unwrap_or_else(|| (random_hex::<16>(), "00".into()));
+            // the proxy is a new span of the trace
+            (trace_id, random_hex::<8>(), flags)
+        };
+        let request_id = headers
+            .get(&REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| valid_request_id(id))
+            .map_or_else(|| trace_id.clone(), ToString::to_string);
+        Self {
+            request_id,
+            trace_id,
+            span_id,
+            flags,
+        }
+    }
+
+    /// W3C `traceparent` value identifying this request's span.
+    pub(crate) fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, self.flags)
+    }
+}
+
+// Returns trace ID and flags of a valid version 00 `traceparent`.
+fn parse_traceparent(value: &str) -> Option<(String, String)> {
+    let mut parts = value.split('-');
+    let (Some(version), Some(trace_id), Some(parent_id), Some(flags), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return None;
+    };
+    let is_hex = |part: &str, len: usize| {
+        part.len() == len
+            && part
+                .bytes()
+                .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    let is_zero = |part: &str| part.bytes().all(|byte| byte == b'0');
+    (version == "00"
+        && is_hex(trace_id, 32)
+        && !is_zero(trace_id)
+        && is_hex(parent_id, 16)
+        && !is_zero(parent_id)
+        && is_hex(flags, 2))
+    .then(|| (trace_id.to_string(), flags.to_string()))
+}
+
+// Request IDs are logged, so only a safe subset of characters is allowed.
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b':'))
+}
+
+fn random_hex<const N: usize>() -> String {
+    rand::random::<[u8; N]>()
+        .iter()
+        .fold(String::with_capacity(2 * N), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// Attach `RequestContext` to the request and return request ID and trace context in response
+/// headers. Must run in the request span, the request ID is recorded in it.
+pub(crate) async fn request_context_middleware(mut request: Request<Body>, next: Next) -> Response {
+    let span = Span::current();
+    let context = RequestContext::new(request.headers(), span.context().span().span_context());
+    span.record("request_id", context.request_id.as_str());
+    request.extensions_mut().insert(context.clone());
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&context.request_id) {
+        headers.insert(&REQUEST_ID_HEADER, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&context.traceparent()) {
+        headers.insert(&TRACEPARENT_HEADER, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::{SpanId, TraceFlags, TraceId, TraceState};
+
+    use super::*;
+
+    #[test]
+    fn test_request_context_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            &TRACEPARENT_HEADER,
+            HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+        let context = RequestContext::new(&headers, &SpanContext::NONE);
+        assert_eq!(context.request_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        let traceparent = context.traceparent();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(traceparent.ends_with("-01"));
+        assert_ne!(
+            traceparent,
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+
+        headers.insert(&REQUEST_ID_HEADER, HeaderValue::from_static("enroll-42"));
+        assert_eq!(
+            RequestContext::new(&headers, &SpanContext::NONE).request_id,
+            "enroll-42"
+        );
+
+        // invalid values are replaced
+        headers.insert(&REQUEST_ID_HEADER, HeaderValue::from_static("a b=c"));
+        headers.insert(
+            &TRACEPARENT_HEADER,
+            HeaderValue::from_static("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+        );
+        let context = RequestContext::new(&headers, &SpanContext::NONE);
+        assert_eq!(context.request_id.len(), 32);
+        assert!(parse_traceparent(&context.traceparent()).is_some());
+    }
+
+    #[test]
+    fn test_request_context_from_span() {
+        let span_context = SpanContext::new(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            SpanId::from_hex("b7ad6b7169203331").unwrap(),
+            TraceFlags::SAMPLED,
+            false,
+            TraceState::default(),
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            &TRACEPARENT_HEADER,
+            HeaderValue::from_static("00-0af7651916cd43dd8448eb211c80319c-00f067aa0ba902b7-00"),
+        );
+        // exported span identifies the request
+        let context = RequestContext::new(&headers, &span_context);
+        assert_eq!(context.request_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(
+            context.traceparent(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-b7ad6b7169203331-01"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use std::io;
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::{
+    sync::CancellationToken,
+    task::{task_tracker::TaskTrackerToken, TaskTracker},
+};
+
+/// Coordinates graceful shutdown. Once shutdown starts, servers stop accepting connections and
+/// long-lived sessions are asked to finish. API requests and websocket sessions are tracked,
+/// so shutdown can wait for them before closing Defguard Core streams.
+#[derive(Clone, Default)]
+pub(crate) struct Shutdown {
+    token: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl Shutdown {
+    /// Start shutdown.
+    pub(crate) fn start(&self) {
+        self.tracker.close();
+        self.token.cancel();
+    }
+
+    /// Completes when shutdown starts.
+    pub(crate) async fn started(self) {
+        self.token.cancelled_owned().await;
+    }
+
+    /// Track a request or session, shutdown waits until the returned token is dropped.
+    pub(crate) fn track(&self) -> TaskTrackerToken {
+        self.tracker.token()
+    }
+
+    /// Completes when shutdown started and all tracked requests and sessions are done.
+    pub(crate) async fn tracked_done(&self) {
+        self.tracker.wait().await;
+    }
+}
+
+/// Track API requests, so they can finish during shutdown.
+pub(crate) async fn track_requests_middleware(
+    State(shutdown): State<Shutdown>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let _token = shutdown.track();
+    next.run(request).await
+}
+
+/// Wait for SIGINT or SIGTERM, returns the signal name.
+pub(crate) async fn shutdown_signal() -> io::Result<&'static str> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = interrupt.recv() => Ok("SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{middleware, routing::get, Router};
+    use tokio::{sync::Notify, time::timeout};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_requests() {
+        let shutdown = Shutdown::default();
+        let started = Arc::new(Notify::new());
+        let finish = Arc::new(Notify::new());
+        let handler = {
+            let (started, finish) = (Arc::clone(&started), Arc::clone(&finish));
+            move || async move {
+                started.notify_one();
+                finish.notified().await;
+            }
+        };
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(middleware::from_fn_with_state(
+                shutdown.clone(),
+                track_requests_middleware,
+            ));
+        let request = tokio::spawn(app.oneshot(Request::get("/").body(Body::empty()).unwrap()));
+        started.notified().await;
+
+        // not done before shutdown starts, even without requests
+        let idle = Shutdown::default();
+        assert!(timeout(Duration::from_millis(50), idle.tracked_done())
+            .await
+            .is_err());
+
+        shutdown.start();
+        timeout(Duration::from_secs(1), shutdown.clone().started())
+            .await
+            .unwrap();
+        // in-flight request is waited for
+        assert!(timeout(Duration::from_millis(50), shutdown.tracked_done())
+            .await
+            .is_err());
+        finish.notify_one();
+        timeout(Duration::from_secs(1), shutdown.tracked_done())
+            .await
+            .unwrap();
+        assert!(request.await.unwrap().unwrap().status().is_success());
+    }
+}
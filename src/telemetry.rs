@@ -0,0 +1,229 @@
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::{
+    ExporterBuildError, MetricExporter, Protocol, SpanExporter, WithExportConfig, WithTonicConfig,
+};
+use opentelemetry_sdk::{
+    metrics::SdkMeterProvider,
+    trace::{Sampler, SdkTracer, SdkTracerProvider},
+    Resource,
+};
+use tonic::transport::ClientTlsConfig;
+use url::Url;
+
+use crate::{
+    config::{Config, OtlpProtocol},
+    metrics::PrometheusReader,
+    VERSION,
+};
+
+const SERVICE_NAME: &str = "defguard-proxy";
+
+/// Exports traces and metrics to OpenTelemetry collector over OTLP, and collects metrics
+/// exposed in Prometheus format.
+pub struct Telemetry {
+    tracer_provider: Option<SdkTracerProvider>,
+    meter_provider: SdkMeterProvider,
+    prometheus: Option<PrometheusReader>,
+}
+
+impl Telemetry {
+    /// Set up exporters if `otlp_endpoint` is configured, and Prometheus metrics if `metrics`
+    /// is enabled. The meter provider is installed globally, so metrics can be recorded anywhere
+    /// with `opentelemetry::global::meter`. gRPC exporters have to be built within Tokio runtime.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ExporterBuildError> {
+        if config.otlp_endpoint.is_none() && !config.metrics {
+            return Ok(None);
+        }
+        let resource = Resource::builder()
+            .with_service_name(SERVICE_NAME)
+            .with_attribute(KeyValue::new("service.version", VERSION))
+            .build();
+
+        let mut tracer_provider = None;
+        let mut meter_provider = SdkMeterProvider::builder().with_resource(resource.clone());
+        if let Some(endpoint) = &config.otlp_endpoint {
+            tracer_provider = Some(
+                SdkTracerProvider::builder()
+                    .with_resource(resource)
+                    .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                        config.otlp_sampling_ratio,
+                    ))))
+                    .with_batch_exporter(span_exporter(endpoint, config.otlp_protocol)?)
+                    .build(),
+            );
+            meter_provider = meter_provider
+                .with_periodic_exporter(metric_exporter(endpoint, config.otlp_protocol)?);
+        }
+        let prometheus = config.metrics.then(PrometheusReader::default);
+        if let Some(reader) = &prometheus {
+            meter_provider = meter_provider.with_reader(reader.clone());
+        }
+        let meter_provider = meter_provider.build();
+        global::set_meter_provider(meter_provider.clone());
+
+        Ok(Some(Self {
+            tracer_provider,
+            meter_provider,
+            prometheus,
+        }))
+    }
+
+    /// Tracer exporting spans, if OTLP export is enabled.
+    #[must_use]
+    pub(crate) fn tracer(&self) -> Option<SdkTracer> {
+        self.tracer_provider
+            .as_ref()
+            .map(|provider| provider.tracer(SERVICE_NAME))
+    }
+
+    /// Reader of metrics exposed in Prometheus format, if enabled.
+    #[must_use]
+    pub fn prometheus(&self) -> Option<PrometheusReader> {
+        self.prometheus.clone()
+    }
+
+    /// Export remaining spans and metrics. Blocks until export is done.
+    pub fn shutdown(&self) {
+        if let Some(provider) = &self.tracer_provider {
+            if let Err(err) = provider.shutdown() {
+                warn!("Failed to export remaining traces: {err}");
+            }
+        }
+        if let Err(err) = self.meter_provider.shutdown() {
+            warn!("Failed to export remaining metrics: {err}");
+        }
+    }
+}
+
+// OTLP/HTTP endpoint configured programmatically is used as is, so append signal path.
+fn signal_url(endpoint: &Url, signal: &str) -> String {
+    format!("{}/v1/{signal}", endpoint.as_str().trim_end_matches('/'))
+}
+
+fn tls_config(endpoint: &Url) -> Option<ClientTlsConfig> {
+    (endpoint.scheme() == "https").then(|| ClientTlsConfig::new().with_native_roots())
+}
+
+fn span_exporter(
+    endpoint: &Url,
+    protocol: OtlpProtocol,
+) -> Result<SpanExporter, ExporterBuildError> {
+    let builder = SpanExporter::builder();
+    match protocol {
+        OtlpProtocol::Grpc => {
+            let builder = builder.with_tonic().with_endpoint(endpoint.as_str());
+            match tls_config(endpoint) {
+                Some(tls) => builder.with_tls_config(tls).build(),
+                None => builder.build(),
+            }
+        }
+        OtlpProtocol::HttpProtobuf => builder
+            .with_http()
+            .with_protocol(Protocol::HttpBinary)
+            .with_endpoint(signal_url(endpoint, "traces"))
+            .build(),
+    }
+}
+
+fn metric_exporter(
+    endpoint: &Url,
+    protocol: OtlpProtocol,
+) -> Result<MetricExporter, ExporterBuildError> {
+    let builder = MetricExporter::builder();
+    match protocol {
+        OtlpProtocol::Grpc => {
+            let builder = builder.with_tonic().with_endpoint(endpoint.as_str());
+            match tls_config(endpoint) {
+                Some(tls) => builder.with_tls_config(tls).build(),
+                None => builder.build(),
+            }
+        }
+        OtlpProtocol::HttpProtobuf => builder
+            .with_http()
+            .with_protocol(Protocol::HttpBinary)
+            .with_endpoint(signal_url(endpoint, "metrics"))
+            .build(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+        time::Duration,
+    };
+
+    use opentelemetry::{metrics::MeterProvider, trace::Tracer};
+
+    use super::*;
+
+    // Minimal OTLP/HTTP collector, reports paths of received requests.
+    fn collector() -> (Url, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap_or_default();
+                let _ = tx.send(path.to_string());
+                reader
+                    .into_inner()
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn test_export_over_http() {
+        let (url, paths) = collector();
+        let config = Config::parse_without_env([
+            "defguard-proxy",
+            "--otlp-endpoint",
+            url.as_str(),
+            "--otlp-protocol",
+            "http-protobuf",
+        ]);
+        let telemetry = Telemetry::from_config(&config).unwrap().unwrap();
+        telemetry.tracer().unwrap().in_span("http_request", |_| ());
+        telemetry
+            .meter_provider
+            .meter("test")
+            .u64_counter("requests")
+            .build()
+            .add(1, &[]);
+        telemetry.shutdown();
+
+        let mut received: Vec<_> = (0..2)
+            .map(|_| paths.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        received.sort();
+        assert_eq!(received, ["/v1/metrics", "/v1/traces"]);
+
+        // export disabled without endpoint
+        let config = Config::parse_without_env(["defguard-proxy"]);
+        assert!(Telemetry::from_config(&config).unwrap().is_none());
+    }
+}
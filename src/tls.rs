@@ -0,0 +1,692 @@
+use std::{
+    fmt,
+    fs::read_to_string,
+    future::pending,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::serve::Listener;
+use notify::{recommended_watcher, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use rustls::{
+    client::danger::HandshakeSignatureValid,
+    crypto::{ring::default_provider, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, UnixTime},
+    server::{
+        danger::{ClientCertVerified, ClientCertVerifier},
+        ClientHello, ResolvesServerCert, VerifierBuilderError, WebPkiClientVerifier,
+    },
+    sign::CertifiedKey,
+    version::{TLS12, TLS13},
+    CertificateError, ClientConfig, DigitallySignedStruct, DistinguishedName, RootCertStore,
+    ServerConfig, SignatureScheme, SupportedProtocolVersion,
+};
+use rustls_native_certs::load_native_certs;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, watch},
+    time::{interval, sleep, timeout},
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use x509_parser::{extensions::GeneralName, parse_x509_certificate};
+
+use crate::{
+    config::TlsVersion,
+    proxy_protocol::{ProxiedStream, TrustedPeers},
+};
+
+// Maximum time a client has to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Interval of periodic certificate re-read.
+const CERT_RELOAD_INTERVAL: Duration = Duration::from_secs(3600);
+// Delay between a file change notification and certificate reload.
+const CERT_RELOAD_DEBOUNCE: Duration = Duration::from_secs(1);
+// Warn about certificates expiring sooner than that.
+const CERT_EXPIRY_WARNING: time::Duration = time::Duration::days(14);
+// Number of established TLS connections waiting to be picked up by the server.
+const TLS_ACCEPT_BACKLOG: usize = 128;
+// Delay before accepting connections again after a failure, e.g. running out of file descriptors.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(thiserror::Error, Debug)]
+pub enum TlsError {
+    #[error("Failed to read certificate or key file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Failed to parse PEM data: {0}")]
+    Pem(#[from] rustls::pki_types::pem::Error),
+    #[error("No certificates found in PEM data")]
+    NoCertificates,
+    #[error("Invalid certificate or key: {0}")]
+    Rustls(#[from] rustls::Error),
+    #[error("Failed to parse certificate: {0}")]
+    X509(String),
+    #[error("Failed to build client certificate verifier: {0}")]
+    Verifier(#[from] VerifierBuilderError),
+}
+
+fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(default_provider())
+}
+
+/// Parses PEM encoded certificate chain and private key and checks that they match.
+pub(crate) fn certified_key(cert_pem: &str, key_pem: &str) -> Result<CertifiedKey, TlsError> {
+    let certs =
+        CertificateDer::pem_slice_iter(cert_pem.as_bytes()).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates);
+    }
+    let key = PrivateKeyDer::from_pem_slice(key_pem.as_bytes())?;
+    Ok(CertifiedKey::from_der(certs, key, &crypto_provider())?)
+}
+
+/// Returns expiry date of the leaf certificate.
+fn not_after(certified_key: &CertifiedKey) -> Result<OffsetDateTime, TlsError> {
+    let cert = certified_key.end_entity_cert()?;
+    let (_, cert) = parse_x509_certificate(cert).map_err(|err| TlsError::X509(err.to_string()))?;
+    Ok(cert.validity().not_after.to_datetime())
+}
+
+/// Format date as RFC 3339 string.
+pub(crate) fn format_date(date: OffsetDateTime) -> String {
+    date.format(&Rfc3339).unwrap_or_else(|_| date.to_string())
+}
+
+struct LoadedCert {
+    certified_key: Arc<CertifiedKey>,
+    not_after: OffsetDateTime,
+}
+
+/// Server certificate resolver which allows swapping the certificate at runtime,
+/// so new TLS handshakes pick up a renewed certificate without restarting the listener.
+pub(crate) struct CertResolver {
+    name: &'static str,
+    cert: RwLock<LoadedCert>,
+    // certificate and key file paths, followed by `watch_cert_files`
+    files: watch::Sender<(String, String)>,
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("name", &self.name)
+            .field("not_after", &self.not_after())
+            .finish_non_exhaustive()
+    }
+}
+
+impl CertResolver {
+    /// Create new `CertResolver` reading PEM encoded certificate and key from given files.
+    /// `name` identifies the certificate in logs and health output.
+    pub(crate) fn from_files(
+        name: &'static str,
+        cert_path: &str,
+        key_path: &str,
+    ) -> Result<Self, TlsError> {
+        let cert = Self::load(&read_to_string(cert_path)?, &read_to_string(key_path)?)?;
+        let resolver = Self {
+            name,
+            cert: RwLock::new(cert),
+            files: watch::Sender::new((cert_path.to_string(), key_path.to_string())),
+        };
+        resolver.log_loaded(cert_path);
+        Ok(resolver)
+    }
+
+    fn load(cert_pem: &str, key_pem: &str) -> Result<LoadedCert, TlsError> {
+        let certified_key = certified_key(cert_pem, key_pem)?;
+        Ok(LoadedCert {
+            not_after: not_after(&certified_key)?,
+            certified_key: Arc::new(certified_key),
+        })
+    }
+
+    fn log_loaded(&self, cert_path: &str) {
+        info!(
+            "Loaded {} TLS certificate from {cert_path}, valid until {}",
+            self.name,
+            format_date(self.not_after())
+        );
+        self.warn_if_expiring();
+    }
+
+    /// Replace served certificate with the one read from given files, which are watched
+    /// for changes from now on. The current certificate is kept if the new one can't be loaded.
+    pub(crate) fn reload_from_files(
+        &self,
+        cert_path: &str,
+        key_path: &str,
+    ) -> Result<(), TlsError> {
+        let cert = Self::load(&read_to_string(cert_path)?, &read_to_string(key_path)?)?;
+        *self.cert.write().unwrap() = cert;
+        self.log_loaded(cert_path);
+        self.files
+            .send_replace((cert_path.to_string(), key_path.to_string()));
+        Ok(())
+    }
+
+    #[must_use]
+    pub(crate) fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Expiry date of the served certificate.
+    #[must_use]
+    pub(crate) fn not_after(&self) -> OffsetDateTime {
+        self.cert.read().unwrap().not_after
+    }
+
+    fn warn_if_expiring(&self) {
+        let not_after = self.not_after();
+        let now = OffsetDateTime::now_utc();
+        if not_after <= now {
+            error!(
+                "{} TLS certificate expired on {}",
+                self.name,
+                format_date(not_after)
+            );
+        } else if not_after - now < CERT_EXPIRY_WARNING {
+            warn!(
+                "{} TLS certificate expires soon, on {}",
+                self.name,
+                format_date(not_after)
+            );
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.cert.read().unwrap().certified_key))
+    }
+}
+
+// Watch parent directories of `paths` instead of the currently watched ones. Directories are
+// watched, since certificates are often replaced by renaming files or swapping symlinks, which
+// wouldn't be noticed when watching files directly.
+fn watch_dirs(
+    name: &str,
+    watcher: &mut RecommendedWatcher,
+    watched: &mut Vec<PathBuf>,
+    paths: [&str; 2],
+) {
+    for dir in watched.drain(..) {
+        let _ = watcher.unwatch(&dir);
+    }
+    for path in paths {
+        let dir = match Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if watched.iter().any(|watched| watched == dir) {
+            continue;
+        }
+        match watcher.watch(dir, RecursiveMode::NonRecursive) {
+            Ok(()) => watched.push(dir.to_path_buf()),
+            Err(err) => warn!("Failed to watch {dir:?} for {name} TLS certificate changes: {err}"),
+        }
+    }
+}
+
+/// Reload certificate whenever its certificate or key file changes. When the resolver is
+/// reloaded from other files, those are watched instead. Files are also re-read periodically,
+/// in case file system notifications are not available.
+pub(crate) async fn watch_cert_files(resolver: Arc<CertResolver>) {
+    let name = resolver.name();
+    let (tx, mut rx) = mpsc::channel(1);
+    let watcher = recommended_watcher(move |event: notify::Result<Event>| match event {
+        Ok(event) if !event.kind.is_access() => {
+            // a pending notification is enough, files are re-read anyway
+            let _ = tx.try_send(());
+        }
+        Ok(_) => (),
+        Err(err) => warn!("Error watching {name} TLS certificate files: {err}"),
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => Some(watcher),
+        Err(err) => {
+            warn!("Failed to watch {name} TLS certificate files, falling back to periodic reload: {err}");
+            None
+        }
+    };
+    let mut watched = Vec::new();
+    let mut paths = resolver.files.subscribe();
+    let mut interval = interval(CERT_RELOAD_INTERVAL);
+    // first tick completes immediately
+    interval.tick().await;
+    loop {
+        let (cert_path, key_path) = paths.borrow_and_update().clone();
+        if let Some(watcher) = &mut watcher {
+            watch_dirs(name, watcher, &mut watched, [&cert_path, &key_path]);
+        }
+        let read_files = || -> io::Result<(String, String)> {
+            Ok((read_to_string(&cert_path)?, read_to_string(&key_path)?))
+        };
+        let mut current = read_files().ok();
+        loop {
+            tokio::select! {
+                Some(()) = rx.recv() => {
+                    // let the other file of the pair be written too
+                    sleep(CERT_RELOAD_DEBOUNCE).await;
+                    while rx.try_recv().is_ok() {}
+                }
+                _ = interval.tick() => resolver.warn_if_expiring(),
+                // resolver was reloaded from other files
+                Ok(()) = paths.changed() => break,
+            }
+
+            match read_files() {
+                Ok(files) if current.as_ref() != Some(&files) => {
+                    match CertResolver::load(&files.0, &files.1) {
+                        Ok(cert) => {
+                            *resolver.cert.write().unwrap() = cert;
+                            resolver.log_loaded(&cert_path);
+                            current = Some(files);
+                        }
+                        Err(err) => warn!(
+                            "Failed to reload {name} TLS certificate, keeping the current one: {err}"
+                        ),
+                    }
+                }
+                Ok(_) => (),
+                Err(err) => warn!("Failed to read {name} TLS certificate files: {err}"),
+            }
+        }
+    }
+}
+
+/// Returns subject common names and alternative names (DNS, URI and email) of the certificate.
+pub(crate) fn cert_names(cert: &CertificateDer<'_>) -> Result<Vec<String>, TlsError> {
+    let (_, cert) = parse_x509_certificate(cert).map_err(|err| TlsError::X509(err.to_string()))?;
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|name| name.as_str().ok())
+        .map(ToString::to_string)
+        .collect();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::DNSName(name)
+                | GeneralName::URI(name)
+                | GeneralName::RFC822Name(name) => names.push((*name).to_string()),
+                _ => (),
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Checks if `name` matches `pattern`, in which `*` matches any sequence of characters.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            // try every possible length of the wildcard match
+            name.char_indices()
+                .map(|(index, _)| index)
+                .chain([name.len()])
+                .any(|index| matches_pattern(rest, &name[index..]))
+        }
+    }
+}
+
+/// Verifies client certificates against CA certificates, and optionally requires
+/// the certificate subject or alternative name to match a pattern.
+#[derive(Debug)]
+struct ClientNameVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    allowed_name: Option<String>,
+}
+
+impl ClientCertVerifier for ClientNameVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.inner.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let verified = self
+            .inner
+            .verify_client_cert(end_entity, intermediates, now)?;
+        let Some(pattern) = &self.allowed_name else {
+            return Ok(verified);
+        };
+        let names = cert_names(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        if names.iter().any(|name| matches_pattern(pattern, name)) {
+            Ok(verified)
+        } else {
+            warn!(
+                "Rejecting client certificate, none of its names match {pattern}: {}",
+                names.join(", ")
+            );
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Build verifier requiring client certificates signed by one of CA certificates from
+/// `ca_path` file. If `allowed_name` is set, one of certificate names must match it.
+pub(crate) fn client_verifier(
+    ca_path: &str,
+    allowed_name: Option<String>,
+) -> Result<Arc<dyn ClientCertVerifier>, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_slice_iter(read_to_string(ca_path)?.as_bytes()) {
+        roots.add(cert?)?;
+    }
+    if roots.is_empty() {
+        return Err(TlsError::NoCertificates);
+    }
+    let inner =
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), crypto_provider()).build()?;
+    Ok(Arc::new(ClientNameVerifier {
+        inner,
+        allowed_name,
+    }))
+}
+
+/// Build rustls client configuration verifying servers with CA certificates from `ca_path`
+/// file, or system trust store if not set. If `identity` certificate and key files are given,
+/// they are used for client authentication.
+pub(crate) fn client_config(
+    ca_path: Option<&str>,
+    identity: Option<(&str, &str)>,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> Result<ClientConfig, TlsError> {
+    let mut roots = RootCertStore::empty();
+    if let Some(ca_path) = ca_path {
+        for cert in CertificateDer::pem_slice_iter(read_to_string(ca_path)?.as_bytes()) {
+            roots.add(cert?)?;
+        }
+    } else {
+        let native = load_native_certs();
+        for err in native.errors {
+            warn!("Failed to load system CA certificates: {err}");
+        }
+        roots.add_parsable_certificates(native.certs);
+    }
+    if roots.is_empty() {
+        return Err(TlsError::NoCertificates);
+    }
+
+    let builder = ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    let mut config = if let Some((cert_path, key_path)) = identity {
+        let certs = CertificateDer::pem_slice_iter(read_to_string(cert_path)?.as_bytes())
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_slice(read_to_string(key_path)?.as_bytes())?;
+        builder.with_client_auth_cert(certs, key)?
+    } else {
+        builder.with_no_client_auth()
+    };
+    config.alpn_protocols = alpn_protocols;
+    Ok(config)
+}
+
+/// Build rustls server configuration serving certificates from the given resolver.
+/// Client certificates are verified with `client_verifier`, if provided.
+pub(crate) fn server_config(
+    resolver: Arc<CertResolver>,
+    alpn_protocols: Vec<Vec<u8>>,
+    min_version: TlsVersion,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+) -> Result<ServerConfig, TlsError> {
+    let versions: &[&SupportedProtocolVersion] = match min_version {
+        TlsVersion::Tls12 => &[&TLS13, &TLS12],
+        TlsVersion::Tls13 => &[&TLS13],
+    };
+    let builder =
+        ServerConfig::builder_with_provider(crypto_provider()).with_protocol_versions(versions)?;
+    let builder = match client_verifier {
+        Some(verifier) => builder.with_client_cert_verifier(verifier),
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(resolver);
+    config.alpn_protocols = alpn_protocols;
+    Ok(config)
+}
+
+/// Log failure to accept a TCP connection. Unless only the connection being accepted failed,
+/// wait before accepting again, so errors like running out of file descriptors don't spin.
+pub(crate) async fn handle_accept_error(err: io::Error) {
+    if matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+    ) {
+        debug!("Failed to accept TCP connection: {err}");
+        return;
+    }
+    error!("Failed to accept TCP connection: {err}");
+    sleep(ACCEPT_ERROR_DELAY).await;
+}
+
+/// Accept connections on `listener` and perform TLS handshakes in the background, preceded by
+/// reading PROXY protocol header if `proxy_protocol` is enabled.
+/// Returns a stream of established TLS connections, suitable for `serve_with_incoming`.
+pub(crate) fn tls_incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    proxy_protocol: Option<TrustedPeers>,
+) -> ReceiverStream<Result<TlsStream<ProxiedStream>, io::Error>> {
+    let (tx, rx) = mpsc::channel(TLS_ACCEPT_BACKLOG);
+    tokio::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    handle_accept_error(err).await;
+                    continue;
+                }
+            };
+            // server is gone, stop accepting connections
+            if tx.is_closed() {
+                break;
+            }
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            let proxy_protocol = proxy_protocol.clone();
+            tokio::spawn(async move {
+                let stream = match ProxiedStream::accept(stream, proxy_protocol.as_ref()).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("Failed to read PROXY protocol header from {addr}: {err}");
+                        return;
+                    }
+                };
+                match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(tls_stream)) => {
+                        let _ = tx.send(Ok(tls_stream)).await;
+                    }
+                    Ok(Err(err)) => warn!("TLS handshake with {addr} failed: {err}"),
+                    Err(_) => warn!("TLS handshake with {addr} timed out"),
+                }
+            });
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/// Listener accepting TLS connections, for use with `axum::serve`.
+pub(crate) struct TlsListener {
+    incoming: ReceiverStream<Result<TlsStream<ProxiedStream>, io::Error>>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub(crate) fn new(
+        listener: TcpListener,
+        acceptor: TlsAcceptor,
+        proxy_protocol: Option<TrustedPeers>,
+    ) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        Ok(Self {
+            incoming: tls_incoming(listener, acceptor, proxy_protocol),
+            local_addr,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<ProxiedStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            match self.incoming.next().await {
+                Some(Ok(stream)) => {
+                    let addr = stream.get_ref().0.remote_addr();
+                    return (stream, addr);
+                }
+                Some(Err(err)) => error!("Failed to accept TLS connection: {err}"),
+                // accept loop never stops
+                None => pending().await,
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+    fn fixture(name: &str) -> String {
+        format!("{FIXTURES}/{name}")
+    }
+
+    // Expiry date of the test certificate.
+    fn fixture_not_after(index: u8) -> OffsetDateTime {
+        let key = CertResolver::load(
+            &read_to_string(fixture(&format!("cert-{index}.pem"))).unwrap(),
+            &read_to_string(fixture(&format!("key-{index}.pem"))).unwrap(),
+        )
+        .unwrap();
+        key.not_after
+    }
+
+    // Copies test certificate and key to `dir`.
+    fn install(index: u8, dir: &Path) {
+        for file in ["cert", "key"] {
+            fs::copy(
+                fixture(&format!("{file}-{index}.pem")),
+                dir.join(format!("{file}.pem")),
+            )
+            .unwrap();
+        }
+    }
+
+    // Waits for the resolver to serve certificate `index`.
+    async fn serves(resolver: &CertResolver, index: u8) -> bool {
+        let expected = fixture_not_after(index);
+        for _ in 0..50 {
+            if resolver.not_after() == expected {
+                return true;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_cert_reload() {
+        let path = |dir: &Path, file: &str| dir.join(file).to_string_lossy().into_owned();
+        let root = env::temp_dir().join(format!("defguard-proxy-tls-{}", std::process::id()));
+        let (first, second) = (root.join("first"), root.join("second"));
+        fs::create_dir_all(&first).unwrap();
+        fs::create_dir_all(&second).unwrap();
+        install(1, &first);
+
+        let resolver = Arc::new(
+            CertResolver::from_files("test", &path(&first, "cert.pem"), &path(&first, "key.pem"))
+                .unwrap(),
+        );
+        assert_eq!(resolver.not_after(), fixture_not_after(1));
+        // mismatched certificate and key are rejected, the current certificate is kept
+        assert!(resolver
+            .reload_from_files(&fixture("cert-2.pem"), &fixture("key-1.pem"))
+            .is_err());
+        assert_eq!(resolver.not_after(), fixture_not_after(1));
+
+        // renewed files are picked up
+        let watcher = tokio::spawn(watch_cert_files(Arc::clone(&resolver)));
+        sleep(Duration::from_millis(100)).await;
+        install(2, &first);
+        assert!(serves(&resolver, 2).await);
+
+        // after reloading from other files, only those are followed
+        install(3, &second);
+        resolver
+            .reload_from_files(&path(&second, "cert.pem"), &path(&second, "key.pem"))
+            .unwrap();
+        assert_eq!(resolver.not_after(), fixture_not_after(3));
+        sleep(Duration::from_millis(100)).await;
+        install(1, &first);
+        assert!(!serves(&resolver, 1).await);
+        install(1, &second);
+        assert!(serves(&resolver, 1).await);
+
+        watcher.abort();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("core.example.com", "core.example.com"));
+        assert!(!matches_pattern("core.example.com", "proxy.example.com"));
+        assert!(matches_pattern("*.example.com", "core.example.com"));
+        assert!(!matches_pattern("*.example.com", "example.com"));
+        assert!(matches_pattern("core-*.example.com", "core-1.example.com"));
+        assert!(matches_pattern(
+            "spiffe://defguard/*",
+            "spiffe://defguard/core"
+        ));
+        assert!(matches_pattern("*", "anything"));
+    }
+}